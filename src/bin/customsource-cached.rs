@@ -0,0 +1,325 @@
+use gstcustomsource::customsource::daemon::{SharedMemory, BLOCK_SIZE, SLOT_SIZE};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// How long to wait for another client fetching a block before fetching it
+/// ourselves, in case that client is stuck on its backend.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A block of a file, identified by the version of the file sent by the
+/// clients, `<size> <modification time> <uri>`, and `offset / BLOCK_SIZE`.
+type Key = (String, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    Ready { slot: usize, generation: u64, len: usize },
+    /// Being fetched by the given client.
+    Pending(u64),
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    key: Option<Key>,
+    last_used: u64,
+}
+
+struct Cache {
+    shm: SharedMemory,
+    entries: HashMap<Key, Entry>,
+    slots: Vec<Slot>,
+    /// The used slots by when they were last used, oldest first.
+    lru: BTreeMap<u64, usize>,
+    free: Vec<usize>,
+    tick: u64,
+}
+
+impl Cache {
+    /// Looks up a block, registering `client` as its fetcher if nobody has
+    /// it. Returns `None` when it is being fetched by another client.
+    fn get(&mut self, key: &Key, client: u64) -> Option<Entry> {
+        self.tick += 1;
+
+        match self.entries.get(key).copied() {
+            Some(Entry::Ready { slot, generation, len }) => {
+                self.lru.remove(&self.slots[slot].last_used);
+                self.lru.insert(self.tick, slot);
+                self.slots[slot].last_used = self.tick;
+                Some(Entry::Ready { slot, generation, len })
+            }
+            Some(Entry::Pending(other)) if other != client => None,
+            _ => {
+                self.entries.insert(key.clone(), Entry::Pending(client));
+                Some(Entry::Pending(client))
+            }
+        }
+    }
+
+    fn put(&mut self, key: Key, data: &[u8]) {
+        let Some(slot) = self.free.pop().or_else(|| self.lru.pop_first().map(|(_, slot)| slot)) else {
+            return;
+        };
+
+        if let Some(old) = self.slots[slot].key.take() {
+            self.entries.remove(&old);
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, slot);
+        let generation = self.shm.write_slot(slot, data);
+        self.slots[slot] = Slot {
+            key: Some(key.clone()),
+            last_used: self.tick,
+        };
+        self.entries.insert(key, Entry::Ready { slot, generation, len: data.len() });
+    }
+
+    /// Forgets the blocks `client` was fetching, so others fetch them.
+    fn abort(&mut self, key: Option<&Key>, client: u64) {
+        self.entries.retain(|entry_key, entry| {
+            *entry != Entry::Pending(client) || key.is_some_and(|key| key != entry_key)
+        });
+    }
+}
+
+struct Shared {
+    cache: Mutex<Cache>,
+    cond: Condvar,
+}
+
+fn parse_block(args: &str) -> Option<Key> {
+    let (index, file) = args.split_once(' ')?;
+    file_size(file)?;
+
+    Some((file.to_owned(), index.parse().ok()?))
+}
+
+/// Size of the file in `<size> <modification time> <uri>`.
+fn file_size(file: &str) -> Option<u64> {
+    let (size, rest) = file.split_once(' ')?;
+    let (modified, uri) = rest.split_once(' ')?;
+
+    if modified.is_empty() || uri.is_empty() {
+        return None;
+    }
+
+    size.parse().ok()
+}
+
+/// Length of the block at `index` of `file`, `None` past its end.
+fn block_len(file: &str, index: u64) -> Option<usize> {
+    let size = file_size(file)?;
+    let start = index.checked_mul(BLOCK_SIZE).filter(|start| *start < size)?;
+
+    Some((size - start).min(BLOCK_SIZE) as usize)
+}
+
+/// User of the process at the other end of `stream`.
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    // SAFETY: plain data filled by the kernel
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `cred` and `len` describe a valid buffer
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(cred.uid)
+}
+
+fn invalid(request: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid request {request:?}"))
+}
+
+fn serve(shared: &Shared, stream: UnixStream, client: u64, blocks_path: &Path) -> io::Result<()> {
+    // The blocks are served to other processes as they were put, only
+    // trust the processes of our own user
+    // SAFETY: always succeeds
+    if peer_uid(&stream)? != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Client of another user"));
+    }
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let slots = shared.cache.lock().unwrap().slots.len();
+    writeln!(writer, "HELLO {slots} {}", blocks_path.display())?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let request = line.trim_end();
+        let (command, args) = request.split_once(' ').unwrap_or((request, ""));
+
+        match command {
+            "GET" => {
+                let key = parse_block(args).ok_or_else(|| invalid(request))?;
+
+                let mut cache = shared.cache.lock().unwrap();
+                let entry = loop {
+                    if let Some(entry) = cache.get(&key, client) {
+                        break entry;
+                    }
+
+                    let (guard, res) = shared.cond.wait_timeout(cache, FETCH_TIMEOUT).unwrap();
+                    cache = guard;
+                    if res.timed_out() {
+                        cache.entries.remove(&key);
+                    }
+                };
+                drop(cache);
+
+                match entry {
+                    Entry::Ready { slot, generation, len } => writeln!(writer, "HIT {slot} {generation} {len}")?,
+                    Entry::Pending(_) => writeln!(writer, "FETCH")?,
+                }
+            }
+            "PUT" => {
+                let (index, rest) = args.split_once(' ').ok_or_else(|| invalid(request))?;
+                let (len, file) = rest.split_once(' ').ok_or_else(|| invalid(request))?;
+                let key = (file.to_owned(), index.parse::<u64>().map_err(|_| invalid(request))?);
+
+                // Only whole blocks of the file, partial ones are not cached
+                let len = len
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|len| Some(*len) == block_len(&key.0, key.1))
+                    .ok_or_else(|| invalid(request))?;
+
+                let mut data = vec![0; len];
+                reader.read_exact(&mut data)?;
+
+                let mut cache = shared.cache.lock().unwrap();
+                if cache.entries.get(&key) == Some(&Entry::Pending(client)) {
+                    cache.put(key, &data);
+                }
+                shared.cond.notify_all();
+                drop(cache);
+
+                writeln!(writer, "OK")?;
+            }
+            "ABORT" => {
+                let key = parse_block(args).ok_or_else(|| invalid(request))?;
+
+                shared.cache.lock().unwrap().abort(Some(&key), client);
+                shared.cond.notify_all();
+
+                writeln!(writer, "OK")?;
+            }
+            _ => return Err(invalid(request)),
+        }
+    }
+}
+
+fn exit_with_error(message: impl std::fmt::Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("Usage: {} <SOCKET> [CACHE_SIZE]", args[0]);
+        println!();
+        println!("Serves a block cache shared by all the customsource elements of the machine");
+        println!("whose `cache-daemon` property is set to SOCKET. CACHE_SIZE is in bytes and");
+        println!("defaults to {DEFAULT_CACHE_SIZE}. The blocks are kept in SOCKET.blocks.");
+        return;
+    }
+
+    let socket = PathBuf::from(&args[1]);
+    let cache_size = match args.get(2) {
+        Some(size) => size.parse::<u64>().unwrap_or_else(|err| exit_with_error(format!("Invalid cache size {size:?}: {err}"))),
+        None => DEFAULT_CACHE_SIZE,
+    };
+    let slots = (cache_size / BLOCK_SIZE).max(1) as usize;
+
+    let mut blocks_path = socket.clone().into_os_string();
+    blocks_path.push(".blocks");
+    let blocks_path = PathBuf::from(blocks_path);
+
+    // Left over by a previous instance. The file is created anew rather
+    // than truncated, so that a symbolic link put in its place is not
+    // followed
+    let _ = std::fs::remove_file(&blocks_path);
+    let blocks = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&blocks_path)
+        .unwrap_or_else(|err| exit_with_error(format!("Could not create {}: {err}", blocks_path.display())));
+    blocks
+        .set_len((slots * SLOT_SIZE) as u64)
+        .unwrap_or_else(|err| exit_with_error(format!("Could not allocate {}: {err}", blocks_path.display())));
+    let shm = SharedMemory::map(&blocks, slots, true)
+        .unwrap_or_else(|err| exit_with_error(format!("Could not map {}: {err}", blocks_path.display())));
+
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .unwrap_or_else(|err| exit_with_error(format!("Could not bind {}: {err}", socket.display())));
+    std::fs::set_permissions(&socket, Permissions::from_mode(0o600))
+        .unwrap_or_else(|err| exit_with_error(format!("Could not restrict {}: {err}", socket.display())));
+
+    println!("Serving {slots} blocks of {BLOCK_SIZE} bytes on {}", socket.display());
+
+    let shared = Arc::new(Shared {
+        cache: Mutex::new(Cache {
+            shm,
+            entries: HashMap::new(),
+            slots: (0..slots).map(|_| Slot::default()).collect(),
+            lru: BTreeMap::new(),
+            free: (0..slots).rev().collect(),
+            tick: 0,
+        }),
+        cond: Condvar::new(),
+    });
+
+    for (client, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Could not accept connection: {err}");
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        let blocks_path = blocks_path.clone();
+        std::thread::spawn(move || {
+            let client = client as u64;
+
+            if let Err(err) = serve(&shared, stream, client, &blocks_path) {
+                eprintln!("Client {client}: {err}");
+            }
+
+            // Let the others fetch what this client was fetching
+            shared.cache.lock().unwrap().abort(None, client);
+            shared.cond.notify_all();
+        });
+    }
+}
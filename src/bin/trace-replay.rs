@@ -0,0 +1,119 @@
+use gst::prelude::*;
+use gstcustomsource::customsource::trace;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 3 {
+        println!(
+            "Usage: {} <TRACE> <LOCATION> [--realtime] [PROPERTY=VALUE...]",
+            args[0]
+        );
+        println!();
+        println!("Replays the reads recorded with the `trace-location` property of customsource");
+        println!("against LOCATION. PROPERTY=VALUE pairs are set on the customsource element,");
+        println!("e.g. `backend=filesrc` or `source::blocksize=4096`. With --realtime, reads are");
+        println!("issued at their recorded time.");
+        return;
+    }
+
+    let entries = trace::read_trace(Path::new(&args[1])).expect("Could not read trace");
+    let location = &args[2];
+
+    let mut realtime = false;
+    let mut properties = Vec::new();
+    for arg in &args[3..] {
+        if arg == "--realtime" {
+            realtime = true;
+        } else {
+            let (name, value) = arg
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid property {arg:?}, expected PROPERTY=VALUE"));
+            properties.push((name, value));
+        }
+    }
+
+    gst::init().expect("Could not init GStreamer");
+    gstcustomsource::plugin_register_static().expect("Could not register customsource plugin");
+
+    let source = gst::ElementFactory::make("customsource")
+        .property("location", location)
+        .build()
+        .expect("Could not create customsource");
+
+    // Also allows setting properties of the inner source, e.g. `source::blocksize=4096`
+    let child_proxy = source.dynamic_cast_ref::<gst::ChildProxy>().unwrap();
+    for (name, value) in properties {
+        let (object, pspec) = child_proxy
+            .lookup(name)
+            .unwrap_or_else(|_| panic!("No property {name:?} on customsource"));
+        object.set_property_from_str(pspec.name(), value);
+    }
+
+    source
+        .set_state(gst::State::Paused)
+        .expect("Could not set customsource to Paused state");
+
+    let srcpad = source.static_pad("src").unwrap();
+
+    let mut latencies = Vec::with_capacity(entries.len());
+    let mut bytes = 0u64;
+    let mut errors = 0usize;
+    let mut mismatches = 0usize;
+
+    let start = Instant::now();
+    for entry in &entries {
+        if realtime {
+            if let Some(delay) = entry.timestamp.checked_sub(start.elapsed()) {
+                std::thread::sleep(delay);
+            }
+        }
+
+        let read_start = Instant::now();
+        let read = match srcpad.range(entry.offset, entry.size) {
+            Ok(buffer) => Some(buffer.size()),
+            Err(gst::FlowError::Eos) => Some(0),
+            Err(err) => {
+                eprintln!("Read of {} bytes at {} failed: {err:?}", entry.size, entry.offset);
+                errors += 1;
+                None
+            }
+        };
+        latencies.push(read_start.elapsed());
+
+        bytes += read.unwrap_or(0) as u64;
+        if read.is_some() && entry.read.is_some() && read != entry.read {
+            mismatches += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    source
+        .set_state(gst::State::Null)
+        .expect("Could not set customsource to Null state");
+
+    latencies.sort_unstable();
+    let percentile = |percentile: usize| {
+        latencies
+            .get(latencies.len().saturating_sub(1) * percentile / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+
+    println!("Reads:       {} ({errors} failed, {mismatches} with a different size than recorded)", entries.len());
+    println!("Bytes:       {bytes}");
+    println!("Duration:    {elapsed:?}");
+    println!(
+        "Throughput:  {:.2} MiB/s",
+        bytes as f64 / elapsed.max(Duration::from_nanos(1)).as_secs_f64() / (1024.0 * 1024.0)
+    );
+    println!(
+        "Latency:     p50 {:?}, p90 {:?}, p99 {:?}",
+        percentile(50),
+        percentile(90),
+        percentile(99)
+    );
+}
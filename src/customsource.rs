@@ -1,11 +1,36 @@
 use gst::glib;
 use gst::prelude::*;
 
+mod advice;
+mod backend;
+mod cache;
+mod coalesce;
+pub mod daemon;
+mod error;
+mod faults;
+mod fetcher;
+mod hash;
+mod identity;
+mod mirror;
+mod mmap;
+pub mod pin;
+mod prefetch;
+mod reader;
+mod rng;
+mod shaping;
+mod sidecar;
+mod stats;
+pub mod trace;
+mod uri;
+mod uring;
+
 glib::wrapper! {
-    pub struct CustomSource(ObjectSubclass<imp::CustomSource>) @extends gst::Bin, gst::Element, gst::Object, @implements gst::URIHandler;
+    pub struct CustomSource(ObjectSubclass<imp::CustomSource>) @extends gst::Bin, gst::Element, gst::Object, @implements gst::ChildProxy, gst::URIHandler;
 }
 
 pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    identity::register_tags();
+
     gst::Element::register(
         Some(plugin),
         "customsource",
@@ -18,10 +43,33 @@ mod imp {
     use gst::glib;
     use gst::prelude::*;
     use gst::subclass::prelude::*;
+
+    use super::advice::AccessAdvisor;
+    use super::backend::{BackendKind, LocalFile};
+    use super::cache::{Asset, BlockCache};
+    use super::coalesce::Coalescer;
+    use super::daemon;
+    use super::error::Error;
+    use super::faults::FaultInjector;
+    use super::fetcher::ChunkFetcher;
+    use super::identity::Identity;
+    use super::mirror::Mirror;
+    use super::mmap::MappedFile;
+    use super::prefetch::{Prefetcher, Progress};
+    use super::uring::UringFile;
+    use super::reader::{Interrupt, ReadError, Reader};
+    use super::shaping::{Shaper, ShapingSettings};
+    use super::sidecar::{self, Sidecar};
+    use super::stats::Stats;
+    use super::trace::TraceWriter;
+    use super::{pin, uri};
+
     use url::Url;
 
-    use std::str::FromStr;
-    use std::{sync::Mutex};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::sync::{Arc, Condvar, Mutex};
 
     use once_cell::sync::Lazy;
 
@@ -33,23 +81,615 @@ mod imp {
         )
     });
 
+    const DEFAULT_READ_TIMEOUT: u64 = 0;
+    const DEFAULT_STATS_INTERVAL: u64 = 0;
+    const DEFAULT_FAULT_SEED: u64 = 0;
+    const DEFAULT_MAX_BITRATE: u64 = 0;
+    const DEFAULT_READ_LATENCY: u64 = 0;
+    const DEFAULT_READ_JITTER: u64 = 0;
+    const DEFAULT_SHAPING_SHARED: bool = false;
+    const DEFAULT_SHARED_CACHE: bool = false;
+    const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+    const DEFAULT_OFFLINE: bool = false;
+    const DEFAULT_DIRECT_IO: bool = false;
+    const DEFAULT_IO_DEPTH: u32 = 4;
+    const DEFAULT_ACCESS_HINTS: bool = false;
+    const DEFAULT_COALESCE_SIZE: u32 = 0;
+    const DEFAULT_FETCH_CONCURRENCY: u32 = 1;
+    const DEFAULT_FETCH_CHUNK_SIZE: u32 = 1024 * 1024;
+    const DEFAULT_PREFETCH_SIZE: u64 = 0;
+    const DEFAULT_ASYNC_PREPARE: bool = false;
+    const DEFAULT_SIDECAR: bool = false;
+
+    /// Bytes prefetched from the position of a seek in bytes, and from the
+    /// offset of `prefetch-range` events without a size.
+    const PREFETCH_SEEK_SIZE: u64 = 2 * 1024 * 1024;
+
+    /// Configuration of the element, kept independently of the inner
+    /// source which is only created when going to READY.
+    #[derive(Debug, Clone)]
+    struct Settings {
+        location: Option<PathBuf>,
+        base_directory: Option<PathBuf>,
+        backend: BackendKind,
+        read_timeout: Option<gst::ClockTime>,
+        stats_interval: Option<gst::ClockTime>,
+        trace_location: Option<PathBuf>,
+        faults: Option<String>,
+        fault_scenario: Option<PathBuf>,
+        fault_seed: u64,
+        shaping: ShapingSettings,
+        shared_cache: bool,
+        cache_size: u64,
+        cache_daemon: Option<PathBuf>,
+        mirror_location: Option<PathBuf>,
+        pin_directory: Option<PathBuf>,
+        offline: bool,
+        direct_io: bool,
+        io_depth: u32,
+        access_hints: bool,
+        coalesce_size: u32,
+        fetch_concurrency: u32,
+        fetch_chunk_size: u32,
+        prefetch_size: u64,
+        async_prepare: bool,
+        sidecar: bool,
+        sidecar_fields: Option<String>,
+        sidecar_markers: Option<String>,
+    }
+
+    impl Default for Settings {
+        fn default() -> Self {
+            Self {
+                location: None,
+                base_directory: None,
+                backend: BackendKind::default(),
+                read_timeout: None,
+                stats_interval: None,
+                trace_location: None,
+                faults: None,
+                fault_scenario: None,
+                fault_seed: DEFAULT_FAULT_SEED,
+                shaping: ShapingSettings::default(),
+                shared_cache: DEFAULT_SHARED_CACHE,
+                cache_size: DEFAULT_CACHE_SIZE,
+                cache_daemon: None,
+                mirror_location: None,
+                pin_directory: None,
+                offline: DEFAULT_OFFLINE,
+                direct_io: DEFAULT_DIRECT_IO,
+                io_depth: DEFAULT_IO_DEPTH,
+                access_hints: DEFAULT_ACCESS_HINTS,
+                coalesce_size: DEFAULT_COALESCE_SIZE,
+                fetch_concurrency: DEFAULT_FETCH_CONCURRENCY,
+                fetch_chunk_size: DEFAULT_FETCH_CHUNK_SIZE,
+                prefetch_size: DEFAULT_PREFETCH_SIZE,
+                async_prepare: DEFAULT_ASYNC_PREPARE,
+                sidecar: DEFAULT_SIDECAR,
+                sidecar_fields: None,
+                sidecar_markers: None,
+            }
+        }
+    }
+
+    impl Settings {
+        fn absolute_location(&self) -> Option<PathBuf> {
+            uri::absolute_path(self.location.as_ref()?, self.base_directory.as_deref())
+        }
+
+        fn url(&self) -> Option<Url> {
+            uri::path_to_uri(self.location.as_ref()?, self.base_directory.as_deref())
+        }
+
+        fn uri(&self) -> Option<String> {
+            self.url().map(String::from)
+        }
+
+        /// `mirror-location`, or else where the location is pinned.
+        fn mirror_path(&self) -> Option<PathBuf> {
+            match &self.mirror_location {
+                Some(mirror_location) => Some(mirror_location.clone()),
+                None => Some(pin::pinned_path(self.pin_directory.as_ref()?, &self.uri()?)),
+            }
+        }
+    }
+
+    /// Reads a range from the backend, see `Reader`. Called from several
+    /// threads when fetching in parallel.
+    type BackendRead = Arc<dyn Fn(u64, u32) -> Result<gst::Buffer, gst::FlowError> + Send + Sync>;
+
+    /// The reads of the reader from the backend, through the coalescer and
+    /// the chunk fetcher.
+    type BackendFetch = dyn FnMut(u64, u32) -> Result<gst::Buffer, gst::FlowError> + Send;
+
     struct State {
-        filesrc: gst::Element,
+        /// `None` for the backends reading the file themselves.
+        source: Option<gst::Element>,
+        backend: BackendKind,
+        url: Option<Url>,
+        size: Option<u64>,
+        /// Read from instead of the inner source: opened by the backends
+        /// reading the file themselves, or the handle shared with the other
+        /// instances with `shared-cache`.
+        file: Option<Arc<LocalFile>>,
+        identity: Option<Identity>,
+        /// Tags and TOC from the sidecar file, with `sidecar`.
+        sidecar_tags: Option<gst::TagList>,
+        sidecar_toc: Option<gst::Toc>,
+    }
+
+    /// Preparation of the source when going to PAUSED, on a worker thread
+    /// with `async-prepare`.
+    #[derive(Debug, Default)]
+    struct Preparation {
+        /// Whether the worker thread is still preparing the source.
+        pending: bool,
+        /// Whether the source pad was activated before the source was
+        /// prepared, e.g. by a downstream element going to PAUSED first.
+        /// Reading is set up once the preparation is done and the reads
+        /// wait for it.
+        activated: bool,
+        flushing: bool,
+        /// Bumped when going back to READY, so that a preparation thread
+        /// left behind knows that it is stale.
+        generation: u64,
     }
 
     pub struct CustomSource {
         srcpad: gst::GhostPad,
+        settings: Mutex<Settings>,
         state: Mutex<Option<State>>,
+        reader: Mutex<Option<Reader>>,
+        stats: Mutex<Stats>,
+        stats_clock_id: Mutex<Option<gst::PeriodicClockId>>,
+        trace: Mutex<Option<TraceWriter>>,
+        faults: Mutex<Option<Arc<FaultInjector>>>,
+        shaper: Mutex<Option<Arc<Shaper>>>,
+        cache: Mutex<Option<BlockCache>>,
+        mirror: Mutex<Option<Mirror>>,
+        advisor: Mutex<Option<AccessAdvisor>>,
+        prefetcher: Mutex<Option<Prefetcher>>,
+        /// Last percentage posted while the reads wait for the prefetcher.
+        buffering: Mutex<Option<i32>>,
+        /// Stream-start and tags, pushed before the first read.
+        pending_events: Mutex<Vec<gst::Event>>,
+        preparation: Mutex<Preparation>,
+        prepared: Condvar,
+        /// Held while preparing, so that a preparation does not overlap
+        /// with the cleanup of a stale one.
+        prepare_lock: Mutex<()>,
     }
 
     impl CustomSource {
-        fn build_state() -> Result<State, glib::Error>{
-            let filesrc = gst::ElementFactory::make("filesrc")
-                .name("filesrc")
+        /// Makes sure the inner source matches the configuration.
+        ///
+        /// The source is created on first use and only recreated when the
+        /// backend changes so that properties set on it through the child
+        /// proxy are kept.
+        fn ensure_state(&self) -> Result<(), Error> {
+            let (backend, url) = {
+                let settings = self.settings.lock().unwrap();
+                let url = settings.url();
+                let backend = settings.backend.for_uri(url.as_ref()).ok_or_else(|| Error::BackendUnavailable {
+                    uri: settings.uri(),
+                    reason: format!("No backend for URI {:?}", settings.uri()),
+                })?;
+
+                (backend, url)
+            };
+
+            let current = self.state.lock().unwrap().as_ref().map(|state| state.backend);
+            if current.is_some_and(|current| current != backend) {
+                gst::debug!(CAT, imp: self, "Backend changed, rebuilding inner source");
+                self.teardown_state();
+            }
+
+            let created = if self.state.lock().unwrap().is_none() {
+                self.setup_state(backend)?
+            } else {
+                None
+            };
+
+            self.apply_uri(url)?;
+
+            if let Some(source) = created {
+                self.obj().emit_by_name::<()>("source-setup", &[&source]);
+
+                if self.obj().current_state() > gst::State::Null {
+                    source.sync_state_with_parent().map_err(|err| Error::BackendUnavailable {
+                        uri: self.error_uri(),
+                        reason: format!("Could not change state of inner source: {err}"),
+                    })?;
+                }
+            }
+
+            Ok(())
+        }
+
+        fn setup_state(&self, backend: BackendKind) -> Result<Option<gst::Element>, Error> {
+            gst::debug!(CAT, imp: self, "Using {backend:?} backend");
+
+            let Some(factory) = backend.factory_name() else {
+                *self.state.lock().unwrap() = Some(State {
+                    source: None,
+                    backend,
+                    url: None,
+                    size: None,
+                    file: None,
+                    identity: None,
+                    sidecar_tags: None,
+                    sidecar_toc: None,
+                });
+
+                return Ok(None);
+            };
+
+            let source = gst::ElementFactory::make(factory)
+                .name("source")
                 .build()
-                .unwrap();
+                .map_err(|_| Error::ElementMissing { factory })?;
+
+            let obj = self.obj();
+            let unavailable = |reason: String| Error::BackendUnavailable { uri: self.error_uri(), reason };
+
+            obj.add(&source)
+                .map_err(|err| unavailable(format!("Could not add source to bin: {err}")))?;
+
+            *self.state.lock().unwrap() = Some(State {
+                source: Some(source.clone()),
+                backend,
+                url: None,
+                size: None,
+                file: None,
+                identity: None,
+                sidecar_tags: None,
+                sidecar_toc: None,
+            });
+
+            self.srcpad.set_target(source.static_pad("src").as_ref())
+                .map_err(|err| unavailable(format!("Could not set ghostpad target: {err}")))?;
+
+            Ok(Some(source))
+        }
+
+        /// Passes the location to the inner source through its URI handler
+        /// interface which, unlike the `location` property, can carry
+        /// non-UTF-8 file names.
+        fn apply_uri(&self, url: Option<Url>) -> Result<(), Error> {
+            let mut state = self.state.lock().unwrap();
+            let Some(state) = state.as_mut() else {
+                return Ok(());
+            };
+
+            if state.url == url {
+                return Ok(());
+            }
+
+            if let (Some(url), Some(source), Some(factory)) = (&url, &state.source, state.backend.factory_name()) {
+                let handler = source.dynamic_cast_ref::<gst::URIHandler>().ok_or_else(|| Error::BackendUnavailable {
+                    uri: Some(url.to_string()),
+                    reason: format!("{factory} is not an URI handler"),
+                })?;
+
+                handler.set_uri(url.as_str()).map_err(|err| Error::BackendUnavailable {
+                    uri: Some(url.to_string()),
+                    reason: format!("Could not set URI on {factory}: {err}"),
+                })?;
+            }
+
+            state.url = url;
+
+            Ok(())
+        }
+
+        fn teardown_state(&self) {
+            let Some(state) = self.state.lock().unwrap().take() else {
+                return;
+            };
+
+            let _ = self.srcpad.set_target(None::<&gst::Pad>);
+            let Some(source) = state.source else {
+                return;
+            };
+
+            let _ = source.set_state(gst::State::Null);
+
+            if let Err(err) = self.obj().remove(&source) {
+                gst::error!(CAT, imp: self, "Could not remove source from bin: {err}");
+            }
+        }
+
+        /// Whether the configuration can't be changed as the inner source is
+        /// already reading from it.
+        fn is_running(&self) -> bool {
+            self.obj().current_state() > gst::State::Ready || self.preparation.lock().unwrap().pending
+        }
+
+        /// Opens the location and everything reading it needs, when going to
+        /// PAUSED.
+        fn prepare(&self) -> Result<(), Error> {
+            let offline = self.settings.lock().unwrap().offline;
+            let check = |_| match offline {
+                true => self.check_offline(),
+                false => self.check_location(),
+            };
+
+            self.ensure_state().and_then(check).and_then(|_| self.start_trace())
+                .and_then(|_| self.prepare_faults()).and_then(|_| self.prepare_mirror())
+                .and_then(|_| self.prepare_sidecar())?;
+
+            self.prepare_shaping();
+
+            self.start_stats_posting();
+
+            Ok(())
+        }
+
+        /// Releases everything `prepare()` set up, when going back to READY.
+        fn unprepare(&self) {
+            self.stop_stats_posting();
+            self.stop_trace();
+            *self.cache.lock().unwrap() = None;
+            *self.advisor.lock().unwrap() = None;
+            *self.faults.lock().unwrap() = None;
+            *self.shaper.lock().unwrap() = None;
+            self.close_mirror();
+
+            if let Some(state) = &mut *self.state.lock().unwrap() {
+                state.file = None;
+                state.size = None;
+                state.identity = None;
+                state.sidecar_tags = None;
+                state.sidecar_toc = None;
+                if let Some(source) = &state.source {
+                    source.set_locked_state(false);
+                }
+            }
+        }
+
+        /// Starts an asynchronous state change to PAUSED, the preparation
+        /// thread is started by `spawn_preparation()` once the pads are
+        /// activated.
+        fn start_preparation(&self) {
+            let mut preparation = self.preparation.lock().unwrap();
+            preparation.pending = true;
+            preparation.flushing = false;
+            drop(preparation);
+
+            let msg = gst::message::AsyncStart::builder().src(&*self.obj()).build();
+            self.parent_handle_message(msg);
+        }
+
+        fn spawn_preparation(&self) -> Result<(), Error> {
+            let obj = self.obj().clone();
+            let generation = self.preparation.lock().unwrap().generation;
+
+            // Not joined, it may be stuck opening the location
+            thread::Builder::new()
+                .name(format!("{}:prepare", obj.name()))
+                .spawn(move || obj.imp().run_preparation(generation))
+                .map(|_| ())
+                .map_err(|err| Error::Thread { name: "preparation", err })
+        }
+
+        fn run_preparation(&self, generation: u64) {
+            // Waits for a preparation left behind to be done and cleaned up
+            let _prepare_lock = self.prepare_lock.lock().unwrap();
+
+            if self.preparation.lock().unwrap().generation != generation {
+                gst::debug!(CAT, imp: self, "Preparation cancelled");
+                return;
+            }
+
+            gst::debug!(CAT, imp: self, "Preparing asynchronously");
+
+            let res = self.prepare();
+
+            let mut preparation = self.preparation.lock().unwrap();
+            if preparation.generation != generation {
+                gst::debug!(CAT, imp: self, "Back to READY while preparing, cleaning up");
+                drop(preparation);
+                self.unprepare();
+
+                return;
+            }
+
+            match res {
+                Ok(()) => {
+                    self.activate_deferred(&mut preparation);
+                    gst::debug!(CAT, imp: self, "Prepared");
+                },
+                Err(err) => err.post(self.obj().upcast_ref()),
+            }
+            self.complete_preparation(&mut preparation);
+        }
+
+        /// Sets up reading if the pad was activated before the source was
+        /// prepared. Called with the preparation locked so that the pad is
+        /// not deactivated meanwhile.
+        fn activate_deferred(&self, preparation: &mut Preparation) {
+            if !preparation.activated {
+                return;
+            }
+
+            gst::debug!(CAT, imp: self, "Setting up the deferred activation");
+            if let Err(err) = self.activate_pull(&self.srcpad, true) {
+                err.log_with_imp(self);
+            }
+
+            preparation.activated = false;
+            self.prepared.notify_all();
+        }
+
+        /// Ends the asynchronous state change, after the preparation or when
+        /// it could not be started.
+        fn complete_preparation(&self, preparation: &mut Preparation) {
+            preparation.pending = false;
+            preparation.activated = false;
+            self.prepared.notify_all();
+
+            let msg = gst::message::AsyncDone::builder().src(&*self.obj()).build();
+            self.parent_handle_message(msg);
+        }
+
+        /// Waits for the preparation to be done and reading to be set up,
+        /// returns `false` if flushing meanwhile.
+        fn wait_prepared(&self) -> bool {
+            let mut preparation = self.preparation.lock().unwrap();
+            while (preparation.pending || preparation.activated) && !preparation.flushing {
+                preparation = self.prepared.wait(preparation).unwrap();
+            }
+
+            !preparation.pending && !preparation.activated
+        }
+
+        /// URI of the current location, for error reporting.
+        fn error_uri(&self) -> Option<String> {
+            self.settings.lock().unwrap().uri()
+        }
+
+        /// Opens the location ourselves before the inner source does so the
+        /// failure can be reported with the errno and the file size is known.
+        ///
+        /// With `shared-cache` the handle and the cached blocks are shared
+        /// with the other instances reading the same file, every backend
+        /// reading from that handle and the inner source not being started.
+        /// With `cache-daemon` the blocks are shared with other processes.
+        /// The `mmap` and `io-uring` backends keep the file they open here
+        /// to read from it, as do the others with `read-timeout`: a read
+        /// abandoned in the inner source would keep holding its pad. With `access-hints` a handle is kept to give page
+        /// cache hints about the file.
+        fn check_location(&self) -> Result<(), Error> {
+            let not_built = || Error::BackendUnavailable {
+                uri: None,
+                reason: String::from("Internal state has not been built"),
+            };
+            let backend = self.state.lock().unwrap().as_ref().map(|state| state.backend).ok_or_else(not_built)?;
+
+            // Nothing stays locked while opening, which may never return for
+            // a remote location
+            let settings = self.settings.lock().unwrap().clone();
+            let Some(location) = settings.absolute_location() else {
+                return Err(Error::BackendUnavailable {
+                    uri: None,
+                    reason: String::from("No location set"),
+                });
+            };
+
+            let to_error = |err| Error::Io {
+                uri: settings.uri(),
+                offset: None,
+                err,
+            };
+
+            let key = uri::canonical_uri(&location).map(String::from).or_else(|| settings.uri()).unwrap_or_default();
+
+            let shared = settings.shared_cache && settings.cache_daemon.is_none();
+            let (file, size) = if shared {
+                let asset = Asset::acquire(&key, settings.cache_size as usize);
+                let (file, size) = asset.open(&location).map_err(to_error)?;
+
+                gst::debug!(CAT, imp: self, "Using shared cache and handle of {key}");
+                *self.cache.lock().unwrap() = Some(BlockCache::Process(asset));
+
+                (file, size)
+            } else {
+                let file = std::fs::File::open(&location).map_err(to_error)?;
+                let size = file.metadata().map_err(to_error)?.len();
+
+                (Arc::new(file), size)
+            };
+
+            let modified = file.metadata().and_then(|metadata| metadata.modified()).ok();
+            let identity = Identity::new(key.clone(), size, modified, backend.nick());
+
+            if let Some(socket) = &settings.cache_daemon {
+                // Reading without the daemon is slower but still works
+                match daemon::Client::connect(socket, &identity) {
+                    Ok(client) => {
+                        gst::debug!(CAT, imp: self, "Using cache daemon at {socket:?} for {key}");
+                        *self.cache.lock().unwrap() = Some(BlockCache::Daemon(Arc::new(client)));
+                    }
+                    Err(err) => gst::warning!(CAT, imp: self, "Could not connect to cache daemon at {socket:?}: {err}"),
+                }
+            }
+
+            let file = match backend {
+                BackendKind::Mmap => {
+                    let mapped = MappedFile::new(file).map_err(to_error)?;
+                    if let Some(err) = mapped.map_error() {
+                        gst::warning!(CAT, imp: self, "Could not map {location:?}, reading it with pread(): {err}");
+                    }
+
+                    Some(Arc::new(LocalFile::Mapped(mapped)))
+                }
+                BackendKind::IoUring => {
+                    let uring = UringFile::open(file, &location, settings.direct_io, settings.io_depth).map_err(to_error)?;
+                    if !uring.is_async() {
+                        gst::info!(CAT, imp: self, "io_uring not available, reading {location:?} with pread()");
+                    }
+
+                    Some(Arc::new(LocalFile::Uring(Box::new(uring))))
+                }
+                _ if shared || settings.read_timeout.is_some() => Some(Arc::new(LocalFile::Pread(file))),
+                _ => None,
+            };
+
+            if settings.access_hints {
+                // Sharing the open file of the backend also lets the kernel
+                // adapt its read-ahead, the inner filesrc opens its own
+                let (file, shared) = match &file {
+                    Some(file) => (file.file().try_clone(), true),
+                    None => (std::fs::File::open(&location), false),
+                };
+
+                *self.advisor.lock().unwrap() = Some(AccessAdvisor::new(file.map_err(to_error)?, size, shared));
+            }
+
+            let mut state = self.state.lock().unwrap();
+            let state = state.as_mut().ok_or_else(not_built)?;
 
-            Ok(State { filesrc })
+            // The inner source is not started when the file is read directly
+            if file.is_some() {
+                if let Some(source) = &state.source {
+                    source.set_locked_state(true);
+                }
+            }
+
+            state.file = file;
+            state.identity = Some(identity);
+            state.size = Some(size);
+
+            Ok(())
+        }
+
+        /// Takes the size of the file from its complete mirror instead of
+        /// opening it, and keeps the inner source from starting.
+        fn check_offline(&self) -> Result<(), Error> {
+            let settings = self.settings.lock().unwrap().clone();
+            let path = settings.mirror_path().ok_or_else(|| Error::Settings {
+                reason: String::from("Offline mode needs mirror-location or pin-directory to be set"),
+            })?;
+
+            let size = Mirror::complete_size(&path).ok_or_else(|| Error::NotAvailableOffline {
+                uri: settings.uri(),
+                reason: format!("{} is not pinned, {path:?} is missing or incomplete", settings.uri().unwrap_or_default()),
+            })?;
+
+            // The modification time of the file is not known, the
+            // stream-id differs from the one when reading it
+            let key = settings.absolute_location().and_then(|location| uri::canonical_uri(&location)).map(String::from);
+
+            let mut state = self.state.lock().unwrap();
+            if let Some(state) = state.as_mut() {
+                state.identity = key.map(|key| Identity::new(key, size, None, "mirror"));
+                state.size = Some(size);
+                if let Some(source) = &state.source {
+                    source.set_locked_state(true);
+                }
+            }
+
+            Ok(())
         }
 
         fn range(
@@ -59,73 +699,949 @@ mod imp {
             buffer: Option<&mut gst::BufferRef>,
             size: u32,
         ) -> Result<gst::PadGetRangeSuccess, gst::FlowError> {
-            let target_src_pad = pad.target().unwrap();
+            let timeout = self.settings.lock().unwrap().read_timeout;
+
+            gst::debug!(CAT, obj: pad, "range: {pad:?}");
+
+            if !self.wait_prepared() {
+                gst::debug!(CAT, obj: pad, "Flushing while preparing");
+                return Err(gst::FlowError::Flushing);
+            }
+
+            let events = std::mem::take(&mut *self.pending_events.lock().unwrap());
+            for event in events {
+                gst::debug!(CAT, obj: pad, "Pushing {event:?}");
+                if !pad.push_event(event) {
+                    gst::debug!(CAT, obj: pad, "Event not handled downstream");
+                }
+            }
+
+            self.stats.lock().unwrap().record_range(offset, size);
+
+            let reader = self.reader.lock().unwrap().clone();
+            let start = Instant::now();
+            let res = match reader {
+                Some(reader) => reader.read(offset, size, timeout),
+                None => Err(ReadError::Cancelled),
+            };
+            if let Ok(new_buffer) = &res {
+                self.stats.lock().unwrap().record_read(offset, new_buffer.size());
+            }
+            self.record_trace(start, offset, size, res.as_ref().ok().map(|buffer| buffer.size()));
+
+            let ret = match res {
+                Ok(new_buffer) => match buffer {
+                    Some(buffer) => {
+                        let map = new_buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+
+                        buffer.set_size(map.size());
+                        buffer.copy_from_slice(0, &map).map_err(|_| gst::FlowError::Error)?;
+
+                        Ok(gst::PadGetRangeSuccess::FilledBuffer)
+                    },
+                    None => Ok(gst::PadGetRangeSuccess::NewBuffer(new_buffer)),
+                },
+                Err(ReadError::Flow(err)) => {
+                    // The error message is posted by the inner source, see
+                    // `handle_message()`, or when detecting the failure
+                    gst::error!(CAT, obj: pad, "Error: {err:?}");
+                    Err(err)
+                },
+                Err(ReadError::Cancelled) => {
+                    gst::debug!(CAT, obj: pad, "Read at offset {offset} cancelled");
+                    Err(gst::FlowError::Flushing)
+                },
+                Err(ReadError::Timeout) => {
+                    Error::Timeout {
+                        uri: self.error_uri(),
+                        offset,
+                        size,
+                        timeout: timeout.unwrap_or(gst::ClockTime::ZERO),
+                    }.post(self.obj().upcast_ref());
+
+                    Err(gst::FlowError::Error)
+                },
+            };
+
+            gst::debug!(CAT, obj: pad, "end range: {ret:?}");
+
+            ret
+        }
+
+        /// Reads a range for the reader, with the faults applied. Only what
+        /// downstream gets is faulty, the cache and the mirror keep what was
+        /// really read.
+        fn read_faulty(
+            &self,
+            offset: u64,
+            size: u32,
+            interrupt: &Interrupt,
+            backend: &mut BackendFetch,
+        ) -> Result<gst::Buffer, ReadError> {
+            let offline = self.settings.lock().unwrap().offline;
+            let faults = self.faults.lock().unwrap().clone();
+
+            match faults {
+                Some(faults) => faults.read(
+                    offset,
+                    size,
+                    |duration| interrupt.sleep(duration),
+                    |offset, size| self.read_range(offset, size, offline, backend),
+                ),
+                None => self.read_range(offset, size, offline, backend),
+            }
+        }
+
+        /// Reads from the mirror, the prefetched data or the backend, and
+        /// fails reads ending before the end of the file.
+        fn read_range(
+            &self,
+            offset: u64,
+            size: u32,
+            offline: bool,
+            backend: &mut BackendFetch,
+        ) -> Result<gst::Buffer, ReadError> {
+            let res = match self.read_mirror(offset, size) {
+                Some(buffer) => Ok(buffer),
+                // The mirror is complete so only reads past its end miss
+                None if offline => Err(ReadError::Flow(gst::FlowError::Eos)),
+                None => {
+                    let prefetcher = self.prefetcher.lock().unwrap().clone();
+                    let prefetched = prefetcher.as_ref().and_then(|prefetcher| prefetcher.lookup(offset, size));
+                    if prefetched.is_none() {
+                        if let Some(progress) = prefetcher.and_then(|prefetcher| prefetcher.progress()) {
+                            if progress.is_behind(offset) {
+                                self.start_buffering(&progress);
+                            }
+                        }
+                    }
+                    let res = match prefetched {
+                        Some(buffer) => Ok(buffer),
+                        None => self.read_backend(offset, size, backend),
+                    };
+                    if let Ok(buffer) = &res {
+                        if let Some(advisor) = self.advisor.lock().unwrap().as_mut() {
+                            advisor.record(offset, buffer.size() as u64);
+                        }
+                        self.write_mirror(offset, buffer);
+                    }
+
+                    res
+                }
+            };
+
+            match res {
+                Ok(buffer) if self.is_short_read(offset, size, buffer.size()) => {
+                    Error::ShortRead {
+                        uri: self.error_uri(),
+                        offset,
+                        requested: size,
+                        read: buffer.size(),
+                    }.post(self.obj().upcast_ref());
+
+                    Err(ReadError::Flow(gst::FlowError::Error))
+                },
+                res => res,
+            }
+        }
+
+        fn read_backend(&self, offset: u64, size: u32, backend: &mut BackendFetch) -> Result<gst::Buffer, ReadError> {
+            let cache = self.cache.lock().unwrap().clone();
+
+            let mut read = |offset, size| self.timed(|| backend(offset, size)).map_err(ReadError::Flow);
+
+            match cache {
+                Some(cache) => cache.read(
+                    offset,
+                    size,
+                    read,
+                    |hit| self.stats.lock().unwrap().record_cache_lookup(hit),
+                ),
+                None => read(offset, size),
+            }
+        }
+
+        /// Records the time `read` takes as backend latency.
+        fn timed<T>(&self, read: impl FnOnce() -> T) -> T {
+            let start = Instant::now();
+            let res = read();
+            self.stats.lock().unwrap().record_backend_latency(start.elapsed());
+
+            res
+        }
+
+        /// Reads for the prefetcher directly from `read`, as the reader is
+        /// busy with the reads of the streaming thread, but still through
+        /// the cache and to the mirror.
+        fn read_prefetch(
+            &self,
+            read: &(dyn Fn(u64, u32) -> Result<gst::Buffer, gst::FlowError> + Send + Sync),
+            offset: u64,
+            size: u32,
+        ) -> Result<gst::Buffer, gst::FlowError> {
+            let cache = self.cache.lock().unwrap().clone();
+
+            let buffer = match cache {
+                Some(cache) => cache
+                    .read(
+                        offset,
+                        size,
+                        |offset, size| self.timed(|| read(offset, size)).map_err(ReadError::Flow),
+                        |hit| self.stats.lock().unwrap().record_cache_lookup(hit),
+                    )
+                    .map_err(|err| match err {
+                        ReadError::Flow(err) => err,
+                        ReadError::Timeout | ReadError::Cancelled => gst::FlowError::Flushing,
+                    })?,
+                None => self.timed(|| read(offset, size))?,
+            };
+
+            self.write_mirror(offset, &buffer);
+
+            Ok(buffer)
+        }
+
+        /// Starts posting buffering messages until the prefetcher fills the
+        /// range the reads caught up with.
+        fn start_buffering(&self, progress: &Progress) {
+            let mut buffering = self.buffering.lock().unwrap();
+            if buffering.is_some() {
+                return;
+            }
+
+            let percent = progress.percent();
+            gst::debug!(CAT, imp: self, "Reads caught up with the prefetcher, buffering at {percent}%");
+            *buffering = Some(percent);
+            drop(buffering);
+
+            self.post_buffering(percent, Some(progress));
+        }
+
+        /// Called by the prefetcher as it progresses.
+        fn update_buffering(&self, progress: &Progress) {
+            let mut buffering = self.buffering.lock().unwrap();
+            let percent = progress.percent();
+            if !buffering.is_some_and(|last| last != percent) {
+                return;
+            }
+
+            *buffering = (percent < 100).then_some(percent);
+            drop(buffering);
+
+            self.post_buffering(percent, Some(progress));
+        }
+
+        /// Stops buffering, so that applications waiting for it resume.
+        fn stop_buffering(&self) {
+            if self.buffering.lock().unwrap().take().is_some() {
+                self.post_buffering(100, None);
+            }
+        }
+
+        fn post_buffering(&self, percent: i32, progress: Option<&Progress>) {
+            let rate = progress.and_then(|progress| progress.rate).map_or(-1, |rate| rate.min(i32::MAX as u64) as i32);
+            let left = progress.and_then(Progress::time_left).map_or(-1, |left| left as i64);
+
+            let msg = gst::message::Buffering::builder(percent)
+                .stats(gst::BufferingMode::Stream, rate, -1, left)
+                .src(&*self.obj())
+                .build();
+
+            let _ = self.obj().post_message(msg);
+        }
+
+        /// Answers BUFFERING queries in bytes or in percent of the file
+        /// with the ranges that can be read without waiting for the backend.
+        fn query_buffering(&self, q: &mut gst::query::Buffering) -> bool {
+            let format = q.format();
+            if format != gst::Format::Bytes && format != gst::Format::Percent {
+                return false;
+            }
+
+            let (size, has_file, source) = match &*self.state.lock().unwrap() {
+                Some(state) => (state.size, state.file.is_some(), state.source.clone()),
+                None => return false,
+            };
+            let Some(size) = size else {
+                return false;
+            };
+            let value = |bytes: u64| match format {
+                gst::Format::Percent if size > 0 => {
+                    let ppm = bytes.min(size) as u128 * *gst::format::Percent::MAX as u128 / size as u128;
+                    gst::GenericFormattedValue::from(gst::format::Percent::from_ppm(ppm as u32))
+                },
+                gst::Format::Percent => gst::GenericFormattedValue::from(gst::format::Percent::MAX),
+                _ => gst::GenericFormattedValue::from(gst::format::Bytes::from_u64(bytes)),
+            };
+
+            let buffering = *self.buffering.lock().unwrap();
+            q.set_percent(buffering.is_some(), buffering.unwrap_or(100));
+
+            // The inner source knows how much of the file it can read right
+            // away, a filesrc all of it
+            let inner = source.filter(|_| !has_file).and_then(|source| {
+                let mut inner = gst::query::Buffering::new(format);
+                source.static_pad("src")?.query(&mut inner).then_some(inner)
+            });
+            let to_bytes = |value: gst::GenericFormattedValue| match value {
+                gst::GenericFormattedValue::Bytes(bytes) => bytes.map(|bytes| *bytes),
+                gst::GenericFormattedValue::Percent(percent) => {
+                    percent.map(|percent| (*percent as u128 * size as u128 / *gst::format::Percent::MAX as u128) as u64)
+                },
+                _ => None,
+            };
+            let inner_ranges = inner.as_ref().map_or_else(Vec::new, |inner| {
+                let (start, stop, _) = inner.range();
+                inner
+                    .ranges()
+                    .into_iter()
+                    .chain([(start, stop)])
+                    .filter_map(|(start, stop)| Some((to_bytes(start)?, to_bytes(stop)?)))
+                    .collect()
+            });
+
+            // Local files are readable right away, unless shaped to emulate
+            // slow storage or read through the cache daemon
+            let (offline, local) = {
+                let settings = self.settings.lock().unwrap();
+                let local = !settings.shaping.is_enabled()
+                    && settings.cache_daemon.is_none()
+                    && (has_file || inner_ranges.iter().any(|&(start, stop)| start == 0 && stop >= size));
+                (settings.offline, local)
+            };
+            let complete = offline || local || self.mirror.lock().unwrap().as_ref().is_some_and(Mirror::is_complete);
+            if complete {
+                q.set_range(value(0), value(size), 0);
+                q.add_buffering_ranges(&[(value(0), value(size))]);
+                return true;
+            }
+
+            let prefetcher = self.prefetcher.lock().unwrap().clone();
+            let mut ranges = prefetcher.as_ref().map(Prefetcher::ranges).unwrap_or_default();
+            if let Some(mirror) = &*self.mirror.lock().unwrap() {
+                ranges.extend_from_slice(mirror.ranges());
+            }
+            if let Some(cache) = &*self.cache.lock().unwrap() {
+                ranges.extend(cache.ranges());
+            }
+            // What the inner source has only counts when it is not slowed
+            // down by the shaping
+            if !self.settings.lock().unwrap().shaping.is_enabled() {
+                ranges.extend(inner_ranges);
+            }
+
+            // Merge the overlapping and adjacent ranges of both
+            ranges.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+            for (start, end) in ranges {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+
+            let merged = merged.into_iter().map(|(start, end)| (value(start), value(end))).collect::<Vec<_>>();
+            q.add_buffering_ranges(&merged);
+
+            if let Some(progress) = prefetcher.and_then(|prefetcher| prefetcher.progress()).filter(|progress| !progress.done) {
+                let left = progress.time_left().map_or(-1, |left| left as i64);
+
+                q.set_range(value(progress.start), value(progress.filled), left);
+                q.set_stats(
+                    gst::BufferingMode::Stream,
+                    progress.rate.map_or(-1, |rate| rate.min(i32::MAX as u64) as i32),
+                    -1,
+                    left,
+                );
+            }
+
+            true
+        }
+
+        /// Prefetches the range of a `prefetch-range` event, whose `offset`
+        /// and optional `size` are in bytes.
+        fn handle_prefetch_range(&self, s: &gst::StructureRef) -> bool {
+            let field = |name: &str| {
+                s.get::<u64>(name)
+                    .ok()
+                    .or_else(|| s.get::<i64>(name).ok().and_then(|value| u64::try_from(value).ok()))
+                    .or_else(|| s.get::<u32>(name).ok().map(u64::from))
+                    .or_else(|| s.get::<i32>(name).ok().and_then(|value| u64::try_from(value).ok()))
+            };
+
+            let Some(offset) = field("offset") else {
+                gst::warning!(CAT, imp: self, "Ignoring prefetch-range without offset: {s:?}");
+                return false;
+            };
+            let size = field("size").unwrap_or(PREFETCH_SEEK_SIZE);
+
+            let prefetcher = self.prefetcher.lock().unwrap();
+            let Some(prefetcher) = prefetcher.as_ref() else {
+                gst::debug!(CAT, imp: self, "Not prefetching, the source is not active or prefetching is disabled");
+                return false;
+            };
+
+            gst::debug!(CAT, imp: self, "Prefetching {size} bytes at offset {offset}");
+            prefetcher.request(offset, size, false);
+
+            true
+        }
+
+        /// Opens the mirror once the size of the file is known.
+        fn prepare_mirror(&self) -> Result<(), Error> {
+            let Some(path) = self.settings.lock().unwrap().mirror_path() else {
+                return Ok(());
+            };
+
+            let size = self.state.lock().unwrap().as_ref().and_then(|state| state.size).unwrap_or(0);
+            let mirror = Mirror::open(&path, size).map_err(|err| Error::MirrorFile { path, err })?;
+
+            match mirror.is_complete() {
+                true => gst::info!(CAT, imp: self, "Reading from complete mirror {:?}", mirror.path()),
+                false => gst::debug!(CAT, imp: self, "Mirroring to {:?}", mirror.path()),
+            }
+            *self.mirror.lock().unwrap() = Some(mirror);
+
+            Ok(())
+        }
+
+        fn close_mirror(&self) {
+            if let Some(mirror) = self.mirror.lock().unwrap().take() {
+                if let Err(err) = mirror.flush() {
+                    gst::warning!(CAT, imp: self, "Could not save mirrored ranges of {:?}: {err}", mirror.path());
+                }
+            }
+        }
+
+        fn read_mirror(&self, offset: u64, size: u32) -> Option<gst::Buffer> {
+            let mut mirror = self.mirror.lock().unwrap();
+
+            match mirror.as_ref()?.read(offset, size) {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    gst::warning!(CAT, imp: self, "Could not read mirror, disabling it: {err}");
+                    *mirror = None;
+                    None
+                }
+            }
+        }
+
+        fn write_mirror(&self, offset: u64, buffer: &gst::Buffer) {
+            let mut guard = self.mirror.lock().unwrap();
+            let Some(mirror) = guard.as_mut() else {
+                return;
+            };
+
+            let res = buffer
+                .map_readable()
+                .map_err(|_| std::io::Error::other("Could not map buffer"))
+                .and_then(|map| mirror.write(offset, &map));
+
+            match res {
+                Ok(true) => {
+                    gst::info!(CAT, imp: self, "Mirror {:?} complete, reading from it from now on", mirror.path());
+                    if let Err(err) = mirror.flush() {
+                        gst::warning!(CAT, imp: self, "Could not save mirrored ranges: {err}");
+                    }
+                },
+                Ok(false) => (),
+                Err(err) => {
+                    gst::warning!(CAT, imp: self, "Could not write mirror, disabling it: {err}");
+                    *guard = None;
+                },
+            }
+        }
+
+        fn is_short_read(&self, offset: u64, requested: u32, read: usize) -> bool {
+            let state = self.state.lock().unwrap();
+            let Some(file_size) = state.as_ref().and_then(|state| state.size) else {
+                return false;
+            };
+
+            (read as u64) < requested as u64 && offset + (read as u64) < file_size
+        }
+
+        fn pad_activate(&self, pad: &gst::GhostPad) -> Result<(), gst::LoggableError> {
+            gst::debug!(CAT, obj: pad, "activate {pad:?}");
+
+            pad.activate_mode(gst::PadMode::Pull, true)?;
+            Ok(())
+        }
+
+        fn src_activatemode(
+            &self,
+            pad: &gst::GhostPad,
+            mode: gst::PadMode,
+            active: bool,
+        ) -> Result<(), gst::LoggableError> {
+            gst::debug!(CAT, obj: pad, "activatemode: {mode:?} ({active:?})");
+
+            match mode {
+                gst::PadMode::Pull => {
+                    // Downstream activates the pad and may already read
+                    // before the element goes to PAUSED, reactivations while
+                    // running keep counting
+                    if active && self.obj().current_state() < gst::State::Paused {
+                        *self.stats.lock().unwrap() = Stats::default();
+                    }
+
+                    let mut preparation = self.preparation.lock().unwrap();
+                    let prepared = self.state.lock().unwrap().as_ref().is_some_and(|state| state.size.is_some());
+                    if active && (preparation.pending || !prepared) {
+                        gst::debug!(CAT, obj: pad, "Not prepared yet, reading is set up once done");
+                        preparation.activated = true;
+                        return Ok(());
+                    }
+                    if !active && preparation.activated {
+                        preparation.activated = false;
+                        self.prepared.notify_all();
+                    }
+                    if preparation.pending {
+                        return Ok(());
+                    }
+                    drop(preparation);
+
+                    self.activate_pull(pad, active)
+                }
+                gst::PadMode::Push => Err(gst::loggable_error!(CAT, "Push mode not supported")),
+                _ => Err(gst::loggable_error!(
+                    CAT,
+                    "Failed to activate the pad in Unknown mode, {:?}",
+                    mode
+                )),
+            }
+        }
+
+        /// Activates the inner source and sets up the reads from the
+        /// backend, or stops them.
+        fn activate_pull(&self, pad: &gst::GhostPad, active: bool) -> Result<(), gst::LoggableError> {
+            let target = pad.target();
+
+            *self.pending_events.lock().unwrap() = match active {
+                true => self.stream_events(),
+                false => Vec::new(),
+            };
+
+            if !active {
+                if let Some(reader) = self.reader.lock().unwrap().take() {
+                    reader.shutdown();
+                }
+                if let Some(prefetcher) = self.prefetcher.lock().unwrap().take() {
+                    prefetcher.shutdown();
+                }
+                self.stop_buffering();
+            }
+
+            // Everything is read from the mirror, the inner source
+            // is not even started
+            let offline = self.settings.lock().unwrap().offline;
+
+            // The inner source is not started either when the file is read
+            // directly
+            let local_read = self.local_read().filter(|_| !offline);
+            let target = target.filter(|_| !offline && local_read.is_none());
+
+            if let Some(target) = &target {
+                target
+                    .activate_mode(gst::PadMode::Pull, active)
+                    .map_err(gst::LoggableError::from)?;
+            }
+
+            if active {
+                let (name, mut fetch) = match offline {
+                    true => (String::from("mirror"), Box::new(|_, _| Err(gst::FlowError::Eos)) as Box<BackendFetch>),
+                    false => self.backend_fetch(target, local_read)?,
+                };
+
+                // Without a timeout there is nothing to stop waiting for,
+                // the streaming thread reads itself, as it does from a
+                // mapping where handing the read over costs more than it
+                let mapped = self.state.lock().unwrap().as_ref().and_then(|state| state.file.as_ref())
+                    .is_some_and(|file| file.is_mapped());
+                let threaded = self.settings.lock().unwrap().read_timeout.is_some() && !mapped;
+                let obj = self.obj().downgrade();
+
+                let reader = Reader::new(&name, threaded, move |offset, size, interrupt| {
+                    let obj = obj.upgrade().ok_or(ReadError::Cancelled)?;
+                    let res = obj.imp().read_faulty(offset, size, interrupt, &mut *fetch);
+                    res
+                })
+                .map_err(|err| {
+                    let err = Error::Thread { name: "reader", err };
+                    err.post(self.obj().upcast_ref());
+
+                    gst::loggable_error!(CAT, "Failed to spawn reader thread")
+                })?;
+
+                *self.reader.lock().unwrap() = Some(reader);
+            }
+
+            Ok(())
+        }
+
+        /// Sets up the reads from the inner source or the file opened by
+        /// the backend, shaped, coalesced and fetched in chunks, and the
+        /// prefetcher reading ahead from them.
+        fn backend_fetch(
+            &self,
+            target: Option<gst::Pad>,
+            local_read: Option<(String, BackendRead)>,
+        ) -> Result<(String, Box<BackendFetch>), gst::LoggableError> {
+            let backend = match target {
+                Some(target) => {
+                    let name = target.name().to_string();
+                    let read: BackendRead = Arc::new(move |offset, size| target.range(offset, size));
+
+                    Some((name, read))
+                }
+                None => local_read,
+            };
+
+            let Some((name, backend_read)) = backend else {
+                let err = Error::BackendUnavailable {
+                    uri: self.error_uri(),
+                    reason: String::from("No inner source to read from"),
+                };
+                err.post(self.obj().upcast_ref());
+
+                return Err(gst::loggable_error!(CAT, "No inner source to read from"));
+            };
+
+            let shaper = self.shaper.lock().unwrap().clone();
+
+            // The backend, shaped as a remote one, only sees the
+            // coalesced requests and the chunks fetched
+            let shaped_read: BackendRead = Arc::new(move |offset, size| match &shaper {
+                Some(shaper) => shaper.read(offset, size, &*backend_read),
+                None => backend_read(offset, size),
+            });
+
+            let settings = self.settings.lock().unwrap();
+            let mut coalescer = (settings.coalesce_size > 0).then(|| Coalescer::new(settings.coalesce_size));
+            let mut fetcher = (settings.fetch_concurrency > 1).then(|| {
+                ChunkFetcher::new(&name, settings.fetch_chunk_size, settings.fetch_concurrency, shaped_read.clone())
+            });
+            let prefetcher = (settings.prefetch_size > 0).then(|| {
+                let obj = self.obj().downgrade();
+                let progress_obj = obj.clone();
+                let read = shaped_read.clone();
+
+                Prefetcher::new(
+                    &name,
+                    settings.prefetch_size,
+                    move |offset, size| {
+                        let obj = obj.upgrade().ok_or(gst::FlowError::Flushing)?;
+                        let res = obj.imp().read_prefetch(&*read, offset, size);
+                        res
+                    },
+                    move |progress| {
+                        if let Some(obj) = progress_obj.upgrade() {
+                            obj.imp().update_buffering(&progress);
+                        }
+                    },
+                )
+            });
+            drop(settings);
+            *self.prefetcher.lock().unwrap() = prefetcher;
+
+            let fetch = move |offset, size| {
+                let mut fetch = |offset, size| match &mut fetcher {
+                    Some(fetcher) => fetcher.read(offset, size),
+                    None => shaped_read(offset, size),
+                };
+
+                match &mut coalescer {
+                    Some(coalescer) => coalescer.read(offset, size, fetch),
+                    None => fetch(offset, size),
+                }
+            };
+
+            Ok((name, Box::new(fetch)))
+        }
+
+        /// Stream-start with a stream-id identifying the file, tags
+        /// describing it and the metadata of its sidecar.
+        fn stream_events(&self) -> Vec<gst::Event> {
+            let state = self.state.lock().unwrap();
+            let Some(state) = state.as_ref() else {
+                return Vec::new();
+            };
+            let Some(identity) = &state.identity else {
+                return Vec::new();
+            };
+
+            // A single tag event as the global tags replace each other
+            let mut tags = identity.tags();
+            if let Some(sidecar_tags) = &state.sidecar_tags {
+                tags.make_mut().insert(sidecar_tags, gst::TagMergeMode::Append);
+            }
+
+            let mut events = vec![
+                gst::event::StreamStart::builder(&identity.stream_id())
+                    .group_id(gst::GroupId::next())
+                    .build(),
+                gst::event::Tag::new(tags),
+            ];
+            if let Some(toc) = &state.sidecar_toc {
+                events.push(gst::event::Toc::new(toc, false));
+            }
+
+            events
+        }
+
+        /// Reads from the file opened by the backends which don't wrap an
+        /// element, if any.
+        fn local_read(&self) -> Option<(String, BackendRead)> {
+            let file = self.state.lock().unwrap().as_ref()?.file.clone()?;
+            let name = String::from(file.name());
+            let obj = self.obj().downgrade();
+
+            let read: BackendRead = Arc::new(move |offset, size| match file.read(offset, size) {
+                Ok(Some(buffer)) => Ok(buffer),
+                Ok(None) => Err(gst::FlowError::Eos),
+                Err(err) => {
+                    if let Some(obj) = obj.upgrade() {
+                        let err = Error::Io {
+                            uri: obj.imp().error_uri(),
+                            offset: Some(offset),
+                            err,
+                        };
+                        err.post(obj.upcast_ref());
+                    }
+
+                    Err(gst::FlowError::Error)
+                }
+            });
+
+            Some((name, read))
+        }
+
+        fn src_event(&self, pad: &gst::GhostPad, event: gst::Event) -> bool {
+            gst::log!(CAT, obj: pad, "Handling event on srcpad {:?}", event.view());
+
+            match event.view() {
+                gst::EventView::FlushStart(..) => self.set_flushing(true),
+                gst::EventView::FlushStop(..) => self.set_flushing(false),
+                gst::EventView::Seek(seek) => {
+                    // Only observed and not handled, the inner source is
+                    // pulled from and seeking it would make it start pushing
+                    let (_, _, start_type, start, _, _) = seek.get();
+                    if let (gst::SeekType::Set, gst::GenericFormattedValue::Bytes(Some(start))) = (start_type, start) {
+                        // Warm up from the new position before the reads
+                        // arrive
+                        if let Some(prefetcher) = self.prefetcher.lock().unwrap().as_ref() {
+                            gst::debug!(CAT, obj: pad, "Seek to byte {start}, prefetching from there");
+                            prefetcher.request(*start, PREFETCH_SEEK_SIZE, true);
+                        }
+                    }
+
+                    return false;
+                },
+                gst::EventView::CustomUpstream(custom) => {
+                    if let Some(s) = custom.structure().filter(|s| s.name() == "prefetch-range") {
+                        return self.handle_prefetch_range(s);
+                    }
+                },
+                _ => (),
+            }
+
+            gst::Pad::event_default(pad, Some(&*self.obj()), event)
+        }
+
+        fn src_query(&self, pad: &gst::GhostPad, query: &mut gst::QueryRef) -> bool {
+            gst::log!(CAT, obj: pad, "Handling query {query:?}");
+
+            // There is no inner source running, answer for it
+            let (own_source, size) = match &*self.state.lock().unwrap() {
+                Some(state) => (state.source.is_none() || state.file.is_some(), state.size),
+                None => (false, None),
+            };
+
+            if let gst::QueryViewMut::Buffering(q) = query.view_mut() {
+                if self.query_buffering(q) {
+                    return true;
+                }
+            }
+
+            if own_source || self.settings.lock().unwrap().offline {
+
+                match query.view_mut() {
+                    gst::QueryViewMut::Scheduling(q) => {
+                        q.set(gst::SchedulingFlags::SEEKABLE, 1, -1, 0);
+                        q.add_scheduling_modes(&[gst::PadMode::Pull]);
+                        return true;
+                    },
+                    gst::QueryViewMut::Duration(q) if q.format() == gst::Format::Bytes => {
+                        let Some(size) = size else {
+                            return false;
+                        };
+
+                        q.set(gst::format::Bytes::from_u64(size));
+                        return true;
+                    },
+                    gst::QueryViewMut::Seeking(q) if q.format() == gst::Format::Bytes => {
+                        let Some(size) = size else {
+                            return false;
+                        };
+
+                        q.set(true, gst::format::Bytes::ZERO, gst::format::Bytes::from_u64(size));
+                        return true;
+                    },
+                    _ => (),
+                }
+            }
+
+            gst::Pad::query_default(pad, Some(&*self.obj()), query)
+        }
+
+        /// Loads the sidecar of the location, with `sidecar`. A sidecar
+        /// that can't be read is only warned about.
+        fn prepare_sidecar(&self) -> Result<(), Error> {
+            let (location, fields, markers) = {
+                let settings = self.settings.lock().unwrap();
+                if !settings.sidecar {
+                    return Ok(());
+                }
+
+                let fields = settings.sidecar_fields.as_deref().map(sidecar::parse_fields).transpose()
+                    .map_err(|reason| Error::Settings { reason })?;
+
+                (settings.absolute_location(), fields, settings.sidecar_markers.clone())
+            };
+
+            let Some(path) = location.as_deref().and_then(Sidecar::find) else {
+                gst::debug!(CAT, imp: self, "No sidecar for {location:?}");
+                return Ok(());
+            };
+
+            let sidecar = match Sidecar::load(&path) {
+                Ok(sidecar) => sidecar,
+                Err(err) => {
+                    gst::warning!(CAT, imp: self, "Ignoring sidecar: {err}");
+                    return Ok(());
+                }
+            };
+
+            let tags = sidecar.tags(fields.as_deref());
+            let toc = markers.and_then(|markers| sidecar.toc(&markers));
+            gst::info!(CAT, imp: self, "Read sidecar {:?}: {tags:?}", sidecar.path());
+
+            if let Some(state) = &mut *self.state.lock().unwrap() {
+                state.sidecar_tags = Some(tags);
+                state.sidecar_toc = toc;
+            }
+
+            Ok(())
+        }
+
+        fn prepare_faults(&self) -> Result<(), Error> {
+            let settings = self.settings.lock().unwrap();
+            if settings.faults.is_none() && settings.fault_scenario.is_none() {
+                return Ok(());
+            }
+
+            let faults = FaultInjector::new(
+                settings.faults.as_deref(),
+                settings.fault_scenario.as_deref(),
+                settings.fault_seed,
+            ).map_err(|reason| Error::Settings { reason })?;
+
+            if !faults.is_empty() {
+                gst::info!(CAT, imp: self, "Injecting faults: {faults:?}");
+                *self.faults.lock().unwrap() = Some(Arc::new(faults));
+            }
+
+            Ok(())
+        }
+
+        fn prepare_shaping(&self) {
+            let settings = self.settings.lock().unwrap();
+            if !settings.shaping.is_enabled() {
+                return;
+            }
+
+            gst::info!(CAT, imp: self, "Shaping reads: {:?}", settings.shaping);
+            *self.shaper.lock().unwrap() = Some(Arc::new(Shaper::new(settings.shaping, settings.fault_seed)));
+        }
 
-            gst::debug!(CAT, obj: pad, "range: {pad:?}");
+        fn start_trace(&self) -> Result<(), Error> {
+            let Some(path) = self.settings.lock().unwrap().trace_location.clone() else {
+                return Ok(());
+            };
 
-            let ret = match buffer {
-                    Some(buffer) => {
-                        match target_src_pad.range_fill(offset, buffer, size) {
-                            Ok(..) => Ok(gst::PadGetRangeSuccess::FilledBuffer),
-                            Err(err) => {
-                                gst::error!(CAT, obj: pad, "Error: {err:?}");
-                                Err(err)
-                            },
-                        }
-                    },
-                    None => {
-                        match target_src_pad.range(offset, size) {
-                            Ok(buffer) => Ok(gst::PadGetRangeSuccess::NewBuffer(buffer)),
-                            Err(err) => {
-                                gst::error!(CAT, obj: pad, "Error: {err:?}");
-                                Err(err)
-                            },
-                        }
-                    }
-                };
+            gst::debug!(CAT, imp: self, "Recording trace to {path:?}");
 
-            gst::debug!(CAT, obj: pad, "end range: {ret:?}");
+            let writer = TraceWriter::create(&path).map_err(|err| Error::TraceFile { path, err })?;
+            *self.trace.lock().unwrap() = Some(writer);
 
-            ret
+            Ok(())
         }
 
-        fn pad_activate(&self, pad: &gst::GhostPad) -> Result<(), gst::LoggableError> {
-            gst::debug!(CAT, obj: pad, "activate {pad:?}");
+        fn stop_trace(&self) {
+            if let Some(mut writer) = self.trace.lock().unwrap().take() {
+                if let Err(err) = writer.flush() {
+                    gst::warning!(CAT, imp: self, "Could not write trace: {err}");
+                }
+            }
+        }
 
-            pad.activate_mode(gst::PadMode::Pull, true)?;
-            Ok(())
+        fn record_trace(&self, start: Instant, offset: u64, size: u32, read: Option<usize>) {
+            let mut trace = self.trace.lock().unwrap();
+            let Some(writer) = trace.as_mut() else {
+                return;
+            };
+
+            if let Err(err) = writer.record(start, offset, size, read) {
+                gst::warning!(CAT, imp: self, "Could not write trace, stopping recording: {err}");
+                *trace = None;
+            }
         }
 
-        fn src_activatemode(
-            &self,
-            pad: &gst::GhostPad,
-            mode: gst::PadMode,
-            active: bool,
-        ) -> Result<(), gst::LoggableError> {
-            gst::debug!(CAT, obj: pad, "activatemode: {mode:?} ({active:?})");
+        /// Starts posting the statistics on the bus every `stats-interval`.
+        fn start_stats_posting(&self) {
+            self.stop_stats_posting();
 
-            match mode {
-                gst::PadMode::Pull => {
-                    pad
-                        .target()
-                        .unwrap()
-                        .activate_mode(mode, active)
-                        .map_err(gst::LoggableError::from)?;
+            let Some(interval) = self.settings.lock().unwrap().stats_interval else {
+                return;
+            };
+
+            let clock = gst::SystemClock::obtain();
+            let clock_id = clock.new_periodic_id(clock.time().unwrap_or(gst::ClockTime::ZERO) + interval, interval);
 
-                    Ok(())
+            let obj_weak = self.obj().downgrade();
+            let res = clock_id.wait_async(move |_clock, _time, _id| {
+                if let Some(obj) = obj_weak.upgrade() {
+                    obj.imp().post_stats();
                 }
-                gst::PadMode::Push => Err(gst::loggable_error!(CAT, "Push mode not supported")),
-                _ => Err(gst::loggable_error!(
-                    CAT,
-                    "Failed to activate the pad in Unknown mode, {:?}",
-                    mode
-                )),
+            });
+
+            match res {
+                Ok(_) => *self.stats_clock_id.lock().unwrap() = Some(clock_id),
+                Err(err) => gst::error!(CAT, imp: self, "Could not schedule stats posting: {err:?}"),
             }
         }
 
-        fn src_event(&self, pad: &gst::GhostPad, event: gst::Event) -> bool {
-            gst::log!(CAT, obj: pad, "Handling event on srcpad {:?}", event.view());
-            gst::Pad::event_default(pad, Some(&*self.obj()), event)
+        fn stop_stats_posting(&self) {
+            if let Some(clock_id) = self.stats_clock_id.lock().unwrap().take() {
+                clock_id.unschedule();
+            }
+        }
+
+        fn post_stats(&self) {
+            let stats = self.stats.lock().unwrap().to_structure();
+            let msg = gst::message::Element::builder(stats).src(&*self.obj()).build();
+
+            let _ = self.obj().post_message(msg);
+        }
+
+        fn set_flushing(&self, flushing: bool) {
+            self.preparation.lock().unwrap().flushing = flushing;
+            self.prepared.notify_all();
+
+            if let Some(reader) = &*self.reader.lock().unwrap() {
+                gst::debug!(CAT, imp: self, "Setting reader flushing: {flushing}");
+                reader.set_flushing(flushing);
+            }
         }
     }
 
@@ -134,7 +1650,7 @@ mod imp {
         const NAME: &'static str = "CustomSource";
         type Type = super::CustomSource;
         type ParentType = gst::Bin;
-        type Interfaces = (gst::URIHandler,);
+        type Interfaces = (gst::ChildProxy, gst::URIHandler);
 
         fn with_class(klass: &Self::Class) -> Self {
             let templ = klass.pad_template("src").unwrap();
@@ -178,11 +1694,34 @@ mod imp {
                         |source| source.src_event(pad, event),
                     )
                 })
+                .query_function(|pad, parent, query| {
+                    CustomSource::catch_panic_pad_function(
+                        parent,
+                        || false,
+                        |source| source.src_query(pad, query),
+                    )
+                })
                 .build();
 
             Self {
                 srcpad,
+                settings: Mutex::new(Settings::default()),
                 state: Mutex::new(None),
+                reader: Mutex::new(None),
+                stats: Mutex::new(Stats::default()),
+                stats_clock_id: Mutex::new(None),
+                trace: Mutex::new(None),
+                faults: Mutex::new(None),
+                shaper: Mutex::new(None),
+                cache: Mutex::new(None),
+                mirror: Mutex::new(None),
+                advisor: Mutex::new(None),
+                prefetcher: Mutex::new(None),
+                buffering: Mutex::new(None),
+                pending_events: Mutex::new(Vec::new()),
+                preparation: Mutex::new(Preparation::default()),
+                prepared: Condvar::new(),
+                prepare_lock: Mutex::new(()),
             }
         } 
     }
@@ -195,82 +1734,397 @@ mod imp {
                         .nick("File location")
                         .blurb("Location of the file to read")
                         .build(),
+                    glib::ParamSpecString::builder("base-directory")
+                        .nick("Base directory")
+                        .blurb("Directory relative locations are resolved against, the current directory if unset")
+                        .build(),
+                    glib::ParamSpecEnum::builder("backend", BackendKind::default())
+                        .nick("Backend")
+                        .blurb("Backend used to read the location")
+                        .build(),
+                    glib::ParamSpecUInt64::builder("read-timeout")
+                        .nick("Read timeout")
+                        .blurb("Time in nanoseconds after which a pending read fails, 0 to wait forever. Reads the file directly instead of through the inner source when set")
+                        .default_value(DEFAULT_READ_TIMEOUT)
+                        .build(),
+                    glib::ParamSpecBoxed::builder::<gst::Structure>("stats")
+                        .nick("Statistics")
+                        .blurb("Statistics about the reads done so far")
+                        .read_only()
+                        .build(),
+                    glib::ParamSpecUInt64::builder("stats-interval")
+                        .nick("Statistics interval")
+                        .blurb("Interval in nanoseconds at which the statistics are posted as element message, 0 to disable")
+                        .default_value(DEFAULT_STATS_INTERVAL)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecString::builder("trace-location")
+                        .nick("Trace location")
+                        .blurb("File to record every read to as JSON lines, for replaying with trace-replay")
+                        .build(),
+                    glib::ParamSpecString::builder("faults")
+                        .nick("Faults")
+                        .blurb("Faults to inject in reads for testing, as ';' separated GstStructures")
+                        .build(),
+                    glib::ParamSpecString::builder("fault-scenario")
+                        .nick("Fault scenario")
+                        .blurb("File with the faults to inject in reads for testing, one GstStructure per line")
+                        .build(),
+                    glib::ParamSpecUInt64::builder("fault-seed")
+                        .nick("Fault seed")
+                        .blurb("Seed of the random generator deciding which reads get faults and their jitter")
+                        .default_value(DEFAULT_FAULT_SEED)
+                        .build(),
+                    glib::ParamSpecUInt64::builder("max-bitrate")
+                        .nick("Maximum bitrate")
+                        .blurb("Maximum bitrate in bits per second at which data is read, 0 for unlimited")
+                        .default_value(DEFAULT_MAX_BITRATE)
+                        .build(),
+                    glib::ParamSpecUInt64::builder("read-latency")
+                        .nick("Read latency")
+                        .blurb("Delay in nanoseconds added to every read")
+                        .default_value(DEFAULT_READ_LATENCY)
+                        .build(),
+                    glib::ParamSpecUInt64::builder("read-jitter")
+                        .nick("Read jitter")
+                        .blurb("Maximum random deviation in nanoseconds from read-latency")
+                        .default_value(DEFAULT_READ_JITTER)
+                        .build(),
+                    glib::ParamSpecBoolean::builder("shaping-shared")
+                        .nick("Shared shaping")
+                        .blurb("Share max-bitrate with all the other instances of the process that enable this")
+                        .default_value(DEFAULT_SHAPING_SHARED)
+                        .build(),
+                    glib::ParamSpecBoolean::builder("shared-cache")
+                        .nick("Shared cache")
+                        .blurb("Share the blocks read and the file handle with the other instances of the process reading the same file")
+                        .default_value(DEFAULT_SHARED_CACHE)
+                        .build(),
+                    glib::ParamSpecUInt64::builder("cache-size")
+                        .nick("Cache size")
+                        .blurb("Maximum number of bytes kept in the shared cache of the file")
+                        .default_value(DEFAULT_CACHE_SIZE)
+                        .build(),
+                    glib::ParamSpecString::builder("mirror-location")
+                        .nick("Mirror location")
+                        .blurb("Local file the data read is mirrored to, read from instead of the backend once complete")
+                        .build(),
+                    glib::ParamSpecString::builder("pin-directory")
+                        .nick("Pin directory")
+                        .blurb("Directory the file is pinned to as it is read, unless mirror-location is set")
+                        .build(),
+                    glib::ParamSpecBoolean::builder("offline")
+                        .nick("Offline")
+                        .blurb("Only read from the complete mirror or pinned file, failing if there is none")
+                        .default_value(DEFAULT_OFFLINE)
+                        .build(),
+                    glib::ParamSpecString::builder("cache-daemon")
+                        .nick("Cache daemon")
+                        .blurb("Socket of a customsource-cached daemon sharing the blocks read with other processes")
+                        .build(),
+                    glib::ParamSpecBoolean::builder("direct-io")
+                        .nick("Direct I/O")
+                        .blurb("Read with O_DIRECT, bypassing the page cache, with the io-uring backend")
+                        .default_value(DEFAULT_DIRECT_IO)
+                        .build(),
+                    glib::ParamSpecUInt::builder("io-depth")
+                        .nick("I/O depth")
+                        .blurb("Number of reads the io-uring backend keeps in flight, including read-ahead")
+                        .minimum(1)
+                        .maximum(64)
+                        .default_value(DEFAULT_IO_DEPTH)
+                        .build(),
+                    glib::ParamSpecBoolean::builder("access-hints")
+                        .nick("Access hints")
+                        .blurb("Tell the kernel how the file is read, and drop the pages already read of huge files from the page cache")
+                        .default_value(DEFAULT_ACCESS_HINTS)
+                        .build(),
+                    glib::ParamSpecUInt::builder("coalesce-size")
+                        .nick("Coalesce size")
+                        .blurb("Size in bytes small reads are aligned to and merged in before reaching the backend, 0 to disable")
+                        .default_value(DEFAULT_COALESCE_SIZE)
+                        .build(),
+                    glib::ParamSpecUInt::builder("fetch-concurrency")
+                        .nick("Fetch concurrency")
+                        .blurb("Number of chunks fetched in parallel from the backend, 1 to read one range at a time")
+                        .minimum(1)
+                        .maximum(64)
+                        .default_value(DEFAULT_FETCH_CONCURRENCY)
+                        .build(),
+                    glib::ParamSpecUInt::builder("fetch-chunk-size")
+                        .nick("Fetch chunk size")
+                        .blurb("Size in bytes of the chunks fetched in parallel with fetch-concurrency")
+                        .minimum(4096)
+                        .default_value(DEFAULT_FETCH_CHUNK_SIZE)
+                        .build(),
+                    glib::ParamSpecUInt64::builder("prefetch-size")
+                        .nick("Prefetch size")
+                        .blurb("Maximum number of bytes kept prefetched for seeks and prefetch-range events, 0 to disable")
+                        .default_value(DEFAULT_PREFETCH_SIZE)
+                        .build(),
+                    glib::ParamSpecBoolean::builder("async-prepare")
+                        .nick("Async prepare")
+                        .blurb("Open the location on a worker thread and go to PAUSED asynchronously")
+                        .default_value(DEFAULT_ASYNC_PREPARE)
+                        .build(),
+                    glib::ParamSpecBoolean::builder("sidecar")
+                        .nick("Sidecar")
+                        .blurb("Push the metadata of a JSON or XML sidecar file next to the location as tags")
+                        .default_value(DEFAULT_SIDECAR)
+                        .build(),
+                    glib::ParamSpecString::builder("sidecar-fields")
+                        .nick("Sidecar fields")
+                        .blurb("Fields of the sidecar pushed as tags, as ',' separated path[=tag], all top-level fields if unset")
+                        .build(),
+                    glib::ParamSpecString::builder("sidecar-markers")
+                        .nick("Sidecar markers")
+                        .blurb("Path of the markers of the sidecar pushed as TOC entries, with start, end and title fields")
+                        .build(),
                 ]
             });
 
             PROPERTIES.as_ref()
         }
 
-        fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
-            let state = self.state.lock().unwrap();
+        fn signals() -> &'static [glib::subclass::Signal] {
+            static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+                vec![
+                    // Emitted with the inner source after it is created and
+                    // before it goes to READY, to let applications configure it
+                    glib::subclass::Signal::builder("source-setup")
+                        .param_types([gst::Element::static_type()])
+                        .build(),
+                ]
+            });
 
+            SIGNALS.as_ref()
+        }
+
+        fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
             gst::debug!(CAT, "Setting property {:?}: {value:?}", pspec.name());
-            if let Some(state) = &*state {
-                match pspec.name() {
-                    "location" => {
-                        let location = value.get::<String>().unwrap();
 
-                        gst::debug!(CAT, imp: self, "Setting filesrc location: {location:?}");
-                        state.filesrc.set_property("location", location.as_str());
-                    },
-                    _ => (),
-                }
-            }
-            else {
-                gst::error!(CAT, "Cannot set properties before internal state has been built");
+            match pspec.name() {
+                "location" | "base-directory" | "backend" | "read-timeout" | "trace-location" | "faults" | "fault-scenario" | "fault-seed"
+                | "max-bitrate" | "read-latency" | "read-jitter" | "shaping-shared" | "shared-cache" | "cache-size"
+                | "cache-daemon" | "mirror-location" | "pin-directory" | "offline" | "direct-io" | "io-depth"
+                | "access-hints" | "coalesce-size" | "fetch-concurrency" | "fetch-chunk-size"
+                | "prefetch-size" | "async-prepare" | "sidecar" | "sidecar-fields" | "sidecar-markers"
+                    if self.is_running() => {
+                    gst::warning!(CAT, imp: self, "Cannot change {:?} while running", pspec.name());
+                },
+                "location" => {
+                    let location = value.get::<Option<String>>().expect("type checked upstream");
+
+                    gst::debug!(CAT, imp: self, "Setting location: {location:?}");
+                    self.settings.lock().unwrap().location = location.map(PathBuf::from);
+                },
+                "base-directory" => {
+                    let base_directory = value.get::<Option<String>>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().base_directory = base_directory.map(PathBuf::from);
+                },
+                "backend" => {
+                    self.settings.lock().unwrap().backend = value.get::<BackendKind>().expect("type checked upstream");
+                },
+                "read-timeout" => {
+                    let timeout = value.get::<u64>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().read_timeout = match timeout {
+                        0 => None,
+                        timeout => Some(gst::ClockTime::from_nseconds(timeout)),
+                    };
+                },
+                "stats-interval" => {
+                    let interval = value.get::<u64>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().stats_interval = match interval {
+                        0 => None,
+                        interval => Some(gst::ClockTime::from_nseconds(interval)),
+                    };
+
+                    if self.is_running() {
+                        self.start_stats_posting();
+                    }
+                },
+                "trace-location" => {
+                    let trace_location = value.get::<Option<String>>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().trace_location = trace_location.map(PathBuf::from);
+                },
+                "faults" => {
+                    self.settings.lock().unwrap().faults = value.get::<Option<String>>().expect("type checked upstream");
+                },
+                "fault-scenario" => {
+                    let scenario = value.get::<Option<String>>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().fault_scenario = scenario.map(PathBuf::from);
+                },
+                "fault-seed" => {
+                    self.settings.lock().unwrap().fault_seed = value.get::<u64>().expect("type checked upstream");
+                },
+                "max-bitrate" => {
+                    let max_bitrate = value.get::<u64>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().shaping.max_bitrate = (max_bitrate != 0).then_some(max_bitrate);
+                },
+                "read-latency" => {
+                    let latency = value.get::<u64>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().shaping.latency = Duration::from_nanos(latency);
+                },
+                "read-jitter" => {
+                    let jitter = value.get::<u64>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().shaping.jitter = Duration::from_nanos(jitter);
+                },
+                "shaping-shared" => {
+                    self.settings.lock().unwrap().shaping.shared = value.get::<bool>().expect("type checked upstream");
+                },
+                "shared-cache" => {
+                    self.settings.lock().unwrap().shared_cache = value.get::<bool>().expect("type checked upstream");
+                },
+                "cache-size" => {
+                    self.settings.lock().unwrap().cache_size = value.get::<u64>().expect("type checked upstream");
+                },
+                "cache-daemon" => {
+                    let socket = value.get::<Option<String>>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().cache_daemon = socket.map(PathBuf::from);
+                },
+                "mirror-location" => {
+                    let mirror_location = value.get::<Option<String>>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().mirror_location = mirror_location.map(PathBuf::from);
+                },
+                "pin-directory" => {
+                    let pin_directory = value.get::<Option<String>>().expect("type checked upstream");
+
+                    self.settings.lock().unwrap().pin_directory = pin_directory.map(PathBuf::from);
+                },
+                "offline" => {
+                    self.settings.lock().unwrap().offline = value.get::<bool>().expect("type checked upstream");
+                },
+                "direct-io" => {
+                    self.settings.lock().unwrap().direct_io = value.get::<bool>().expect("type checked upstream");
+                },
+                "io-depth" => {
+                    self.settings.lock().unwrap().io_depth = value.get::<u32>().expect("type checked upstream");
+                },
+                "access-hints" => {
+                    self.settings.lock().unwrap().access_hints = value.get::<bool>().expect("type checked upstream");
+                },
+                "coalesce-size" => {
+                    self.settings.lock().unwrap().coalesce_size = value.get::<u32>().expect("type checked upstream");
+                },
+                "fetch-concurrency" => {
+                    self.settings.lock().unwrap().fetch_concurrency = value.get::<u32>().expect("type checked upstream");
+                },
+                "fetch-chunk-size" => {
+                    self.settings.lock().unwrap().fetch_chunk_size = value.get::<u32>().expect("type checked upstream");
+                },
+                "prefetch-size" => {
+                    self.settings.lock().unwrap().prefetch_size = value.get::<u64>().expect("type checked upstream");
+                },
+                "async-prepare" => {
+                    self.settings.lock().unwrap().async_prepare = value.get::<bool>().expect("type checked upstream");
+                },
+                "sidecar" => {
+                    self.settings.lock().unwrap().sidecar = value.get::<bool>().expect("type checked upstream");
+                },
+                "sidecar-fields" => {
+                    self.settings.lock().unwrap().sidecar_fields = value.get::<Option<String>>().expect("type checked upstream");
+                },
+                "sidecar-markers" => {
+                    self.settings.lock().unwrap().sidecar_markers = value.get::<Option<String>>().expect("type checked upstream");
+                },
+                name => gst::warning!(CAT, imp: self, "Unknown property {name:?}"),
             }
         }
 
         fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
             gst::debug!(CAT, "Getting property: {:?}", pspec.name());
 
-            let state = self.state.lock().unwrap();
+            match pspec.name() {
+                "location" => {
+                    let settings = self.settings.lock().unwrap();
 
-            if let Some(state) = &*state {
-                match pspec.name() {
-                    "location" => {
-                        state.filesrc.property::<Option<String>>("location").to_value()
-                    },
-                    _ => unimplemented!(),
-                }
-            }
-            else {
-                gst::element_error!(
-                    self.obj(),
-                    gst::LibraryError::Failed,
-                    ["Cannot get property before state has been built"]);
+                    settings.location.as_ref().map(|location| location.to_string_lossy().into_owned()).to_value()
+                },
+                "base-directory" => {
+                    let settings = self.settings.lock().unwrap();
 
-                unimplemented!()
-            }
-        }
+                    settings.base_directory.as_ref().map(|base| base.to_string_lossy().into_owned()).to_value()
+                },
+                "backend" => self.settings.lock().unwrap().backend.to_value(),
+                "read-timeout" => {
+                    let settings = self.settings.lock().unwrap();
 
-        fn constructed(&self) {
-            self.parent_constructed();
+                    settings.read_timeout.map_or(DEFAULT_READ_TIMEOUT, gst::ClockTime::nseconds).to_value()
+                },
+                "stats" => self.stats.lock().unwrap().to_structure().to_value(),
+                "faults" => self.settings.lock().unwrap().faults.to_value(),
+                "fault-scenario" => {
+                    let settings = self.settings.lock().unwrap();
 
-            *self.state.lock().unwrap() = match CustomSource::build_state() {
-                Ok(state) => {
-                    let obj = self.obj();
+                    settings.fault_scenario.as_ref().map(|path| path.to_string_lossy().into_owned()).to_value()
+                },
+                "fault-seed" => self.settings.lock().unwrap().fault_seed.to_value(),
+                "max-bitrate" => self.settings.lock().unwrap().shaping.max_bitrate.unwrap_or(DEFAULT_MAX_BITRATE).to_value(),
+                "read-latency" => (self.settings.lock().unwrap().shaping.latency.as_nanos() as u64).to_value(),
+                "read-jitter" => (self.settings.lock().unwrap().shaping.jitter.as_nanos() as u64).to_value(),
+                "shaping-shared" => self.settings.lock().unwrap().shaping.shared.to_value(),
+                "shared-cache" => self.settings.lock().unwrap().shared_cache.to_value(),
+                "cache-size" => self.settings.lock().unwrap().cache_size.to_value(),
+                "mirror-location" => {
+                    let settings = self.settings.lock().unwrap();
 
-                    obj.add_many(&[
-                        &state.filesrc,
-                    ]).expect("Could not add elements to bin");
+                    settings.mirror_location.as_ref().map(|path| path.to_string_lossy().into_owned()).to_value()
+                },
+                "pin-directory" => {
+                    let settings = self.settings.lock().unwrap();
 
-                    self.srcpad.set_target(Some(&state.filesrc.static_pad("src").unwrap())).expect("Set ghostpad target failed");
+                    settings.pin_directory.as_ref().map(|path| path.to_string_lossy().into_owned()).to_value()
+                },
+                "offline" => self.settings.lock().unwrap().offline.to_value(),
+                "direct-io" => self.settings.lock().unwrap().direct_io.to_value(),
+                "io-depth" => self.settings.lock().unwrap().io_depth.to_value(),
+                "access-hints" => self.settings.lock().unwrap().access_hints.to_value(),
+                "coalesce-size" => self.settings.lock().unwrap().coalesce_size.to_value(),
+                "fetch-concurrency" => self.settings.lock().unwrap().fetch_concurrency.to_value(),
+                "fetch-chunk-size" => self.settings.lock().unwrap().fetch_chunk_size.to_value(),
+                "prefetch-size" => self.settings.lock().unwrap().prefetch_size.to_value(),
+                "async-prepare" => self.settings.lock().unwrap().async_prepare.to_value(),
+                "sidecar" => self.settings.lock().unwrap().sidecar.to_value(),
+                "sidecar-fields" => self.settings.lock().unwrap().sidecar_fields.to_value(),
+                "sidecar-markers" => self.settings.lock().unwrap().sidecar_markers.to_value(),
+                "cache-daemon" => {
+                    let settings = self.settings.lock().unwrap();
 
-                    if let Err(err) = obj.add_pad(&self.srcpad) {
-                        gst::error!(CAT, imp: self, "Error adding pad to element: {err:?}");
-                        None
-                    }
-                    else {
-                        Some(state) 
-                    }
+                    settings.cache_daemon.as_ref().map(|path| path.to_string_lossy().into_owned()).to_value()
                 },
-                Err(err) => {
-                    gst::error!(CAT, imp: self, "Error building state: {err:?}");
-                    None
+                "trace-location" => {
+                    let settings = self.settings.lock().unwrap();
+
+                    settings.trace_location.as_ref().map(|path| path.to_string_lossy().into_owned()).to_value()
+                },
+                "stats-interval" => {
+                    let settings = self.settings.lock().unwrap();
+
+                    settings.stats_interval.map_or(DEFAULT_STATS_INTERVAL, gst::ClockTime::nseconds).to_value()
+                },
+                name => {
+                    gst::warning!(CAT, imp: self, "Unknown property {name:?}");
+                    pspec.default_value().clone()
                 },
             }
+        }
+
+        fn constructed(&self) {
+            self.parent_constructed();
 
+            if let Err(err) = self.obj().add_pad(&self.srcpad) {
+                gst::error!(CAT, imp: self, "Error adding pad to element: {err:?}");
+            }
         }
     }
 
@@ -306,6 +2160,18 @@ mod imp {
             PAD_TEMPLATES.as_ref()
         }
 
+        fn send_event(&self, event: gst::Event) -> bool {
+            // The bin would only send upstream events to its sink elements,
+            // let applications send prefetch-range to the element itself
+            if let gst::EventView::CustomUpstream(custom) = event.view() {
+                if let Some(s) = custom.structure().filter(|s| s.name() == "prefetch-range") {
+                    return self.handle_prefetch_range(s);
+                }
+            }
+
+            self.parent_send_event(event)
+        }
+
         fn change_state(
             &self,
             transition: gst::StateChange,
@@ -313,15 +2179,121 @@ mod imp {
 
             gst::debug!(CAT, imp: self, "{transition:?}");
 
+            match transition {
+                gst::StateChange::NullToReady => {
+                    if let Err(err) = self.ensure_state() {
+                        err.post(self.obj().upcast_ref());
+                        return Err(gst::StateChangeError);
+                    }
+                },
+                gst::StateChange::ReadyToPaused => {
+                    // Opening a remote location can take long, don't block
+                    // the application meanwhile
+                    if self.settings.lock().unwrap().async_prepare {
+                        self.start_preparation();
+                    } else {
+                        let _prepare_lock = self.prepare_lock.lock().unwrap();
+                        if let Err(err) = self.prepare() {
+                            err.post(self.obj().upcast_ref());
+                            return Err(gst::StateChangeError);
+                        }
+
+                        self.activate_deferred(&mut self.preparation.lock().unwrap());
+                    }
+                },
+                gst::StateChange::PausedToReady => {
+                    // A preparation still running is abandoned rather than
+                    // waited for, it cleans up after itself once done
+                    let mut preparation = self.preparation.lock().unwrap();
+                    preparation.generation += 1;
+                    if preparation.pending {
+                        self.complete_preparation(&mut preparation);
+                    }
+                    drop(preparation);
+
+                    // Unblock any pending read so that the pads can be deactivated
+                    self.set_flushing(true);
+                    self.unprepare();
+                },
+                _ => (),
+            }
+
             // Call the parent class' implementation of ::change_state()
-            let ret = self.parent_change_state(transition);
+            let mut ret = self.parent_change_state(transition);
+
+            if transition == gst::StateChange::ReadyToPaused && self.preparation.lock().unwrap().pending {
+                match ret {
+                    Ok(_) => match self.spawn_preparation() {
+                        Ok(()) => ret = Ok(gst::StateChangeSuccess::Async),
+                        Err(err) => {
+                            err.post(self.obj().upcast_ref());
+                            self.complete_preparation(&mut self.preparation.lock().unwrap());
+                            ret = Err(gst::StateChangeError);
+                        },
+                    },
+                    Err(_) => self.complete_preparation(&mut self.preparation.lock().unwrap()),
+                }
+            }
+
+            if ret.is_err() && transition == gst::StateChange::NullToReady {
+                self.teardown_state();
+            }
 
             gst::debug!(CAT, imp: self, "{ret:?}");
             ret
         }
     }
 
-    impl BinImpl for CustomSource {}
+    impl BinImpl for CustomSource {
+        fn handle_message(&self, msg: gst::Message) {
+            let gst::MessageView::Error(err) = msg.view() else {
+                self.parent_handle_message(msg);
+                return;
+            };
+
+            // Errors from the inner source are posted as our own so they
+            // carry the URI being read
+            let mut details = err.details()
+                .map(|details| details.to_owned())
+                .unwrap_or_else(|| gst::Structure::new_empty("customsource-error"));
+            details.set("uri", self.error_uri());
+            if let Some(src) = msg.src() {
+                details.set("element", src.name().as_str());
+            }
+
+            let debug = err.debug();
+            let mut builder = gst::message::Error::builder_from_error(err.error())
+                .src(&*self.obj())
+                .details(details);
+            if let Some(debug) = &debug {
+                builder = builder.debug(debug);
+            }
+
+            self.parent_handle_message(builder.build());
+        }
+    }
+
+    impl ChildProxyImpl for CustomSource {
+        fn child_by_name(&self, name: &str) -> Option<glib::Object> {
+            // Create the inner source on demand so that its properties can be
+            // set before going to READY, e.g. from gst-launch
+            if name == "source" {
+                if let Err(err) = self.ensure_state() {
+                    gst::warning!(CAT, imp: self, "Could not create inner source: {err:?}");
+                }
+            }
+
+            self.parent_child_by_name(name)
+        }
+
+        fn child_by_index(&self, index: u32) -> Option<glib::Object> {
+            self.parent_child_by_index(index)
+        }
+
+        fn children_count(&self) -> u32 {
+            self.parent_children_count()
+        }
+    }
 
     impl URIHandlerImpl for CustomSource {
         const URI_TYPE: gst::URIType = gst::URIType::Src;
@@ -331,63 +2303,99 @@ mod imp {
         }
 
         fn uri(&self) -> Option<String> {
-            let state = self.state.lock().unwrap();
-
-            if let Some(state) = &*state {
-                let location: String = state.filesrc.property("location");
-                let url = Url::from_file_path(location)
-                    .expect("CustomSource::get_uri couldn't build `Url` from `location`");
-                
-                Some (
-                    String::from(url.as_str())
-                )
-            }
-            else {
-                gst::error!(CAT, imp: self, "Cannot get uri before state has been built");
-                None
-            }
+            self.settings.lock().unwrap().uri()
         }
 
         fn set_uri(&self, uri: &str) -> Result<(), glib::Error> {
-            let uri = String::from(uri);
-            let location = {
-                if uri.starts_with("file://") {
-
-                    let url = Url::from_str(uri.as_str()).unwrap();
-                    match url.to_file_path() {
-                        Ok(file_path) =>  { 
-                            let file_path = file_path
-                                .as_os_str()
-                                .to_str();
-                            
-                            if let Some(file_path) = file_path {
-                                Some(String::from(file_path))
-                            }
-                            else {
-                                None
-                            }
-                        },
-                        Err(_) => None,
-                    }
-                }
-                else {
-                    None
-                }
-            };
+            if self.is_running() {
+                return Err(glib::Error::new(
+                    gst::URIError::BadState,
+                    "Cannot change the URI while running",
+                ));
+            }
+
+            let location = uri::uri_to_path(uri)?;
 
             gst::debug!(CAT, imp: self, "Location: {location:?}");
 
-            if let Some(location) = location {
-                self.instance().set_property("location", location.as_str());
+            self.settings.lock().unwrap().location = Some(location);
 
-                Ok(())
-            }
-            else {
-                Err(
-                    glib::Error::new(
-                        gst::URIError::BadUri, 
-                        "Could not set file location")
-                )
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CustomSource;
+
+    use gst::glib;
+    use gst::prelude::*;
+
+    fn new_source() -> CustomSource {
+        gst::init().unwrap();
+
+        glib::Object::new::<CustomSource>(&[])
+    }
+
+    #[test]
+    fn unset_location() {
+        let source = new_source();
+
+        assert_eq!(source.property::<Option<String>>("location"), None);
+        assert_eq!(source.uri(), None);
+        assert!(source.property::<gst::Structure>("stats").has_name("customsource-stats"));
+
+        // Failing to open nothing is an error, not a panic
+        assert!(source.set_state(gst::State::Paused).is_err());
+        source.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn relative_location() {
+        let source = new_source();
+
+        source.set_property("location", "clips/a b.mov");
+        source.set_property("base-directory", "/media");
+        assert_eq!(source.uri().as_deref(), Some("file:///media/clips/a%20b.mov"));
+        assert_eq!(source.property::<Option<String>>("location").as_deref(), Some("clips/a b.mov"));
+
+        source.set_property("base-directory", None::<String>);
+        let cwd = std::env::current_dir().unwrap();
+        let expected = url::Url::from_file_path(cwd.join("clips/a b.mov")).unwrap();
+        assert_eq!(source.uri().as_deref(), Some(expected.as_str()));
+    }
+
+    #[test]
+    fn malformed_uris() {
+        let source = new_source();
+        source.set_uri("file:///media/a.mov").unwrap();
+
+        for (uri, code) in [
+            ("not an uri", gst::URIError::BadUri),
+            ("file://[::1/a.mov", gst::URIError::BadUri),
+            ("http://example.com/a.mov", gst::URIError::UnsupportedProtocol),
+            ("file://example.com/a.mov", gst::URIError::BadReference),
+        ] {
+            let err = source.set_uri(uri).unwrap_err();
+            assert_eq!(err.kind::<gst::URIError>(), Some(code), "{uri}");
+        }
+
+        // The location is kept on errors
+        assert_eq!(source.uri().as_deref(), Some("file:///media/a.mov"));
+    }
+
+    #[test]
+    fn unknown_properties() {
+        let source = new_source();
+        assert!(source.find_property("unknown").is_none());
+
+        // Every property of the element can be read and written back
+        for pspec in source.list_properties().iter().filter(|pspec| pspec.owner_type() == CustomSource::static_type()) {
+            let value = source.property_value(pspec.name());
+            if pspec.flags().contains(glib::ParamFlags::WRITABLE) {
+                source.set_property_from_value(pspec.name(), &value);
+                assert_eq!(source.property_value(pspec.name()).type_(), value.type_(), "{}", pspec.name());
             }
         }
     }
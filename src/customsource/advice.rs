@@ -0,0 +1,138 @@
+//! Page cache hints for the local file, from the pattern of the reads.
+//!
+//! Once the reads look sequential the kernel is told so and asked to read
+//! ahead of them, and for huge files the pages already consumed are dropped
+//! from the page cache as they are unlikely to be read again by a one-pass
+//! render and would evict the pages other processes need. A seek switches
+//! back to the default behaviour.
+//!
+//! The sequential advice applies to an open file description, not to the
+//! file, so it is only given when the handle is shared with the backend.
+//! With the inner filesrc, which opens the file on its own, only the
+//! read-ahead and drop-behind advice reach the page cache.
+
+use std::fs::File;
+
+/// Consecutive contiguous reads after which the reads are considered
+/// sequential.
+const SEQUENTIAL_READS: u32 = 4;
+/// How far ahead of the current position the kernel is asked to read.
+const WILLNEED_WINDOW: u64 = 8 * 1024 * 1024;
+/// Files from which consumed pages are dropped.
+const DROP_BEHIND_MIN_SIZE: u64 = 1024 * 1024 * 1024;
+/// Distance behind the current position from which pages are dropped, so
+/// that small backward reads of demuxers still hit the page cache.
+const DROP_BEHIND_LAG: u64 = 4 * 1024 * 1024;
+/// Pages are dropped in batches of at least this size.
+const DROP_BEHIND_BATCH: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    Sequential,
+    WillNeed,
+    DontNeed,
+}
+
+#[derive(Debug)]
+pub struct AccessAdvisor {
+    file: File,
+    size: u64,
+    /// Whether `file` is the open file description the backend reads.
+    shared: bool,
+    /// End of the previous read.
+    position: Option<u64>,
+    contiguous_reads: u32,
+    sequential: bool,
+    /// End of the range the kernel was last asked to read ahead.
+    willneed_until: u64,
+    /// Start of the range not dropped yet.
+    dropped_until: u64,
+}
+
+impl AccessAdvisor {
+    /// `file` is a handle on the file being read, `shared` when it shares
+    /// its open file description with the backend so that the sequential
+    /// advice changes the read-ahead of the backend.
+    pub fn new(file: File, size: u64, shared: bool) -> Self {
+        AccessAdvisor {
+            file,
+            size,
+            shared,
+            position: None,
+            contiguous_reads: 0,
+            sequential: false,
+            willneed_until: 0,
+            dropped_until: 0,
+        }
+    }
+
+    /// Records a read of `len` bytes at `offset`.
+    pub fn record(&mut self, offset: u64, len: u64) {
+        let end = offset + len;
+
+        if self.position == Some(offset) {
+            self.contiguous_reads += 1;
+        } else {
+            if self.sequential {
+                if self.shared {
+                    self.advise(Advice::Normal, 0, 0);
+                }
+                self.sequential = false;
+            }
+
+            self.contiguous_reads = 0;
+            self.willneed_until = end;
+            self.dropped_until = offset;
+        }
+        self.position = Some(end);
+
+        if !self.sequential && self.contiguous_reads >= SEQUENTIAL_READS {
+            if self.shared {
+                self.advise(Advice::Sequential, 0, 0);
+            }
+            self.sequential = true;
+        }
+
+        if !self.sequential {
+            return;
+        }
+
+        // Ask for the next window when half of the previous one was read
+        if end + WILLNEED_WINDOW / 2 >= self.willneed_until && self.willneed_until < self.size {
+            let start = self.willneed_until.max(end);
+            let until = (end + WILLNEED_WINDOW).min(self.size);
+
+            self.advise(Advice::WillNeed, start, until - start);
+            self.willneed_until = until;
+        }
+
+        if self.size >= DROP_BEHIND_MIN_SIZE {
+            let until = end.saturating_sub(DROP_BEHIND_LAG);
+
+            if until >= self.dropped_until + DROP_BEHIND_BATCH {
+                self.advise(Advice::DontNeed, self.dropped_until, until - self.dropped_until);
+                self.dropped_until = until;
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn advise(&self, advice: Advice, offset: u64, len: u64) {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            Advice::Normal => libc::POSIX_FADV_NORMAL,
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+
+        // Only hints, failures don't matter
+        // SAFETY: plain libc call on a file we have open
+        unsafe { libc::posix_fadvise(self.file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice) };
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn advise(&self, _advice: Advice, _offset: u64, _len: u64) {}
+}
@@ -0,0 +1,122 @@
+use gst::glib;
+
+use url::Url;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use super::mmap::MappedFile;
+use super::uring::UringFile;
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstCustomSourceBackend")]
+pub enum BackendKind {
+    #[default]
+    #[enum_value(name = "Auto: pick the backend from the URI", nick = "auto")]
+    Auto,
+    #[enum_value(name = "Filesrc: read through a filesrc element", nick = "filesrc")]
+    Filesrc,
+    #[enum_value(name = "Mmap: read local files through a memory mapping", nick = "mmap")]
+    Mmap,
+    #[enum_value(name = "io_uring: read local files with several reads in flight", nick = "io-uring")]
+    IoUring,
+}
+
+impl BackendKind {
+    /// Resolves `Auto` to the backend handling the scheme of `uri`.
+    pub fn for_uri(self, uri: Option<&Url>) -> Option<BackendKind> {
+        match self {
+            BackendKind::Auto => match uri.map(Url::scheme) {
+                Some("file") | None => Some(BackendKind::Filesrc),
+                Some(_) => None,
+            },
+            kind => Some(kind),
+        }
+    }
+
+    pub fn nick(self) -> &'static str {
+        match self {
+            BackendKind::Auto => "auto",
+            BackendKind::Filesrc => "filesrc",
+            BackendKind::Mmap => "mmap",
+            BackendKind::IoUring => "io-uring",
+        }
+    }
+
+    /// Factory of the element the backend wraps, if it reads through one.
+    pub fn factory_name(self) -> Option<&'static str> {
+        match self {
+            BackendKind::Auto | BackendKind::Filesrc => Some("filesrc"),
+            BackendKind::Mmap | BackendKind::IoUring => None,
+        }
+    }
+}
+
+/// Reads `size` bytes at `offset` with `pread()`, `None` at the end of the
+/// file.
+pub fn pread(file: &File, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+    let mut data = vec![0; size as usize];
+    let mut read = 0;
+
+    while read < data.len() {
+        match file.read_at(&mut data[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    if read == 0 && size > 0 {
+        return Ok(None);
+    }
+
+    data.truncate(read);
+
+    Ok(Some(gst::Buffer::from_mut_slice(data)))
+}
+
+/// A file read directly instead of through the inner source.
+pub enum LocalFile {
+    Mapped(MappedFile),
+    Uring(Box<UringFile>),
+    /// The handle read with pread() instead of the inner source: the one
+    /// shared by the instances reading the file with `shared-cache`, or
+    /// the own handle with `read-timeout`.
+    Pread(Arc<File>),
+}
+
+impl LocalFile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LocalFile::Mapped(_) => "mmap",
+            LocalFile::Uring(_) => "io-uring",
+            LocalFile::Pread(_) => "pread",
+        }
+    }
+
+    pub fn file(&self) -> &File {
+        match self {
+            LocalFile::Mapped(file) => file.file(),
+            LocalFile::Uring(file) => file.file(),
+            LocalFile::Pread(file) => file,
+        }
+    }
+
+    /// Whether the file is read from its mapping.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, LocalFile::Mapped(file) if file.is_mapped())
+    }
+
+    /// Reads `size` bytes at `offset`, `None` at the end of the file.
+    pub fn read(&self, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+        match self {
+            LocalFile::Mapped(file) => file.read(offset, size),
+            LocalFile::Uring(file) => file.read(offset, size),
+            LocalFile::Pread(file) => pread(file, offset, size),
+        }
+    }
+}
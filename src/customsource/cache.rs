@@ -0,0 +1,347 @@
+//! Block cache shared by the `CustomSource` instances of the process.
+//!
+//! GES creates several sources for the same asset (one per clip plus the
+//! discoverer's), so with `shared-cache` enabled the instances reading the
+//! same canonical URI share the blocks fetched from the backend and the
+//! handle opened on the file. An asset is dropped when the last instance
+//! using it goes back to READY.
+//!
+//! With `cache-daemon` the blocks are shared with other processes instead,
+//! see the `daemon` module.
+//!
+//! Buffers served from the cache wrap the blocks without copying them, as
+//! long as the read fits in one block, see [`read_blocks()`].
+
+use once_cell::sync::Lazy;
+
+use super::daemon;
+use super::reader::ReadError;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Size of the blocks fetched from the backend and kept in the cache.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+static ASSETS: Lazy<Mutex<HashMap<String, Weak<Asset>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether a fetched block is worth keeping: a short block is only valid
+/// if it is the last one of the file, otherwise the read was interrupted.
+pub fn is_complete(index: u64, len: usize, file_size: Option<u64>) -> bool {
+    len as u64 == BLOCK_SIZE || file_size.is_some_and(|size| index * BLOCK_SIZE + len as u64 == size)
+}
+
+/// Reads the block at `index` through `fetch`.
+pub fn fetch_block(
+    index: u64,
+    fetch: &mut impl FnMut(u64, u32) -> Result<gst::Buffer, ReadError>,
+) -> Result<Arc<[u8]>, ReadError> {
+    let buffer = fetch(index * BLOCK_SIZE, BLOCK_SIZE as u32)?;
+    let map = buffer.map_readable().map_err(|_| ReadError::Flow(gst::FlowError::Error))?;
+
+    Ok(Arc::from(map.as_slice()))
+}
+
+/// Part of a cached block, to wrap it in a `gst::Memory` without copying.
+struct BlockSlice {
+    block: Arc<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl AsRef<[u8]> for BlockSlice {
+    fn as_ref(&self) -> &[u8] {
+        &self.block[self.start..self.end]
+    }
+}
+
+/// Assembles `size` bytes at `offset` from the blocks returned by `block`
+/// for the index of every block overlapping the range.
+///
+/// The buffer has one memory per block, sharing the block instead of
+/// copying it. This is only zero-copy for reads within one block: GStreamer
+/// merges the memories into a new one when a buffer spanning several blocks
+/// is mapped, so reads larger than `BLOCK_SIZE`, or crossing a block
+/// boundary, are still copied once by whoever maps them.
+pub fn read_blocks(
+    offset: u64,
+    size: u32,
+    mut block: impl FnMut(u64) -> Result<Arc<[u8]>, ReadError>,
+) -> Result<gst::Buffer, ReadError> {
+    let mut buffer = gst::Buffer::new();
+    let mut read = 0;
+    let mut index = offset / BLOCK_SIZE;
+
+    while read < size as usize {
+        let block_offset = index * BLOCK_SIZE;
+
+        let block = match block(index) {
+            Ok(block) => block,
+            // The file ends on a block boundary
+            Err(ReadError::Flow(gst::FlowError::Eos)) if read > 0 => break,
+            Err(err) => return Err(err),
+        };
+
+        let start = offset.saturating_sub(block_offset) as usize;
+        if start >= block.len() {
+            break;
+        }
+
+        let len = (block.len() - start).min(size as usize - read);
+        let is_last = (block.len() as u64) < BLOCK_SIZE;
+
+        let memory = gst::Memory::from_slice(BlockSlice {
+            block,
+            start,
+            end: start + len,
+        });
+        buffer.get_mut().unwrap().append_memory(memory);
+        read += len;
+
+        if is_last {
+            break;
+        }
+
+        index += 1;
+    }
+
+    if read == 0 {
+        return Err(ReadError::Flow(gst::FlowError::Eos));
+    }
+
+    Ok(buffer)
+}
+
+/// Where the blocks of a file are shared.
+#[derive(Debug, Clone)]
+pub enum BlockCache {
+    /// With the other instances of the process.
+    Process(Arc<Asset>),
+    /// With the other processes through a `customsource-cached` daemon.
+    Daemon(Arc<daemon::Client>),
+}
+
+impl BlockCache {
+    /// Reads `size` bytes at `offset` from the cached blocks, fetching the
+    /// missing ones through `fetch`. `lookup` is called for every block
+    /// with whether it was found in the cache.
+    pub fn read(
+        &self,
+        offset: u64,
+        size: u32,
+        fetch: impl FnMut(u64, u32) -> Result<gst::Buffer, ReadError>,
+        lookup: impl FnMut(bool),
+    ) -> Result<gst::Buffer, ReadError> {
+        match self {
+            BlockCache::Process(asset) => asset.read(offset, size, fetch, lookup),
+            BlockCache::Daemon(client) => client.read(offset, size, fetch, lookup),
+        }
+    }
+
+    /// Ranges of the file in the cache, the blocks of the daemon are not
+    /// known without asking for them.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        match self {
+            BlockCache::Process(asset) => asset.ranges(),
+            BlockCache::Daemon(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Block {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+/// Least recently used blocks, indexed by `offset / BLOCK_SIZE`.
+#[derive(Debug, Default)]
+struct Blocks {
+    blocks: HashMap<u64, Block>,
+    /// The index of the blocks by when they were last used, oldest first.
+    lru: BTreeMap<u64, u64>,
+    size: usize,
+    max_size: usize,
+    tick: u64,
+}
+
+impl Blocks {
+    fn get(&mut self, index: u64) -> Option<Arc<[u8]>> {
+        let block = self.blocks.get_mut(&index)?;
+
+        self.tick += 1;
+        self.lru.remove(&block.last_used);
+        self.lru.insert(self.tick, index);
+        block.last_used = self.tick;
+
+        Some(block.data.clone())
+    }
+
+    fn insert(&mut self, index: u64, data: Arc<[u8]>) {
+        if data.len() > self.max_size {
+            return;
+        }
+
+        if let Some(old) = self.blocks.remove(&index) {
+            self.lru.remove(&old.last_used);
+            self.size -= old.data.len();
+        }
+
+        while self.size + data.len() > self.max_size {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+
+            if let Some(block) = self.blocks.remove(&oldest) {
+                self.size -= block.data.len();
+            }
+        }
+
+        self.tick += 1;
+        self.size += data.len();
+        self.lru.insert(self.tick, index);
+        self.blocks.insert(index, Block { data, last_used: self.tick });
+    }
+}
+
+#[derive(Debug)]
+pub struct Asset {
+    uri: String,
+    handle: Mutex<Option<(Arc<File>, u64)>>,
+    blocks: Mutex<Blocks>,
+}
+
+impl Asset {
+    /// Returns the asset of `uri`, shared with the other instances using
+    /// it. The cache holds at most the biggest `max_size` asked for.
+    pub fn acquire(uri: &str, max_size: usize) -> Arc<Asset> {
+        let mut assets = ASSETS.lock().unwrap();
+
+        if let Some(asset) = assets.get(uri).and_then(Weak::upgrade) {
+            let mut blocks = asset.blocks.lock().unwrap();
+            blocks.max_size = blocks.max_size.max(max_size);
+            drop(blocks);
+
+            return asset;
+        }
+
+        let asset = Arc::new(Asset {
+            uri: uri.to_owned(),
+            handle: Mutex::new(None),
+            blocks: Mutex::new(Blocks {
+                max_size,
+                ..Default::default()
+            }),
+        });
+        assets.insert(uri.to_owned(), Arc::downgrade(&asset));
+
+        asset
+    }
+
+    /// Opens `path`, or reuses the handle another instance opened, and
+    /// returns it with the size of the file.
+    pub fn open(&self, path: &Path) -> io::Result<(Arc<File>, u64)> {
+        let mut handle = self.handle.lock().unwrap();
+        if let Some((file, size)) = &*handle {
+            return Ok((file.clone(), *size));
+        }
+
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        let file = Arc::new(file);
+        *handle = Some((file.clone(), size));
+
+        Ok((file, size))
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.handle.lock().unwrap().as_ref().map(|(_, size)| *size)
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        let blocks = self.blocks.lock().unwrap();
+
+        blocks
+            .blocks
+            .iter()
+            .map(|(index, block)| (index * BLOCK_SIZE, index * BLOCK_SIZE + block.data.len() as u64))
+            .collect()
+    }
+
+    fn read(
+        &self,
+        offset: u64,
+        size: u32,
+        mut fetch: impl FnMut(u64, u32) -> Result<gst::Buffer, ReadError>,
+        mut lookup: impl FnMut(bool),
+    ) -> Result<gst::Buffer, ReadError> {
+        read_blocks(offset, size, |index| {
+            let cached = self.blocks.lock().unwrap().get(index);
+            lookup(cached.is_some());
+
+            if let Some(block) = cached {
+                return Ok(block);
+            }
+
+            let block = fetch_block(index, &mut fetch)?;
+            if is_complete(index, block.len(), self.size()) {
+                self.blocks.lock().unwrap().insert(index, block.clone());
+            }
+
+            Ok(block)
+        })
+    }
+}
+
+impl Drop for Asset {
+    fn drop(&mut self) {
+        let mut assets = ASSETS.lock().unwrap();
+
+        // Another instance might have acquired a new asset for this URI
+        // since the last reference was dropped
+        if assets.get(&self.uri).is_some_and(|asset| asset.strong_count() == 0) {
+            assets.remove(&self.uri);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Arc<[u8]> {
+        vec![0; len].into()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut blocks = Blocks {
+            max_size: 3,
+            ..Default::default()
+        };
+
+        blocks.insert(0, block(1));
+        blocks.insert(1, block(1));
+        blocks.insert(2, block(1));
+        assert!(blocks.get(0).is_some());
+
+        blocks.insert(3, block(1));
+        assert!(blocks.get(1).is_none());
+        assert!(blocks.get(0).is_some());
+
+        // Replacing a block does not evict another one
+        blocks.insert(2, block(1));
+        assert_eq!(blocks.size, 3);
+        assert_eq!(blocks.lru.len(), 3);
+
+        blocks.insert(4, block(2));
+        assert!(blocks.get(3).is_none());
+        assert!(blocks.get(0).is_none());
+        assert!(blocks.get(2).is_some());
+        assert_eq!(blocks.size, 3);
+    }
+}
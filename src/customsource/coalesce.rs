@@ -0,0 +1,108 @@
+//! Coalescing of small reads into large aligned ones.
+//!
+//! Demuxers read headers and indexes with many tiny reads. Requests are
+//! extended to `alignment` boundaries before reaching the backend and the
+//! data read is kept, so the following reads falling in it are served
+//! without a request and the reads overlapping its end only request what
+//! is missing.
+
+/// Data read by the last backend request.
+#[derive(Debug)]
+struct Window {
+    offset: u64,
+    buffer: gst::Buffer,
+}
+
+impl Window {
+    fn end(&self) -> u64 {
+        self.offset + self.buffer.size() as u64
+    }
+
+    /// `size` bytes at `offset`, truncated at the end of the window.
+    fn slice(&self, offset: u64, size: u32) -> Result<gst::Buffer, gst::FlowError> {
+        let start = (offset - self.offset) as usize;
+        let size = (size as usize).min(self.buffer.size() - start);
+
+        self.buffer
+            .copy_region(gst::BufferCopyFlags::MEMORY, start, Some(size))
+            .map_err(|_| gst::FlowError::Error)
+    }
+}
+
+#[derive(Debug)]
+pub struct Coalescer {
+    alignment: u64,
+    window: Option<Window>,
+    /// Whether the window ends at the end of the file.
+    eos: bool,
+}
+
+impl Coalescer {
+    pub fn new(alignment: u32) -> Self {
+        Coalescer {
+            alignment: alignment.max(1) as u64,
+            window: None,
+            eos: false,
+        }
+    }
+
+    /// Reads through `read`, which is only called with the part of the
+    /// aligned range that was not read before.
+    pub fn read(
+        &mut self,
+        offset: u64,
+        size: u32,
+        read: impl FnOnce(u64, u32) -> Result<gst::Buffer, gst::FlowError>,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        let end = offset + size as u64;
+
+        // Keep what is still useful of the previous request
+        let kept = self.window.take().filter(|window| window.offset <= offset && offset < window.end());
+
+        if let Some(window) = &kept {
+            if end <= window.end() || self.eos {
+                let buffer = window.slice(offset, size)?;
+                self.window = kept;
+                return Ok(buffer);
+            }
+        }
+
+        let start = match &kept {
+            Some(window) => window.end(),
+            None => offset - offset % self.alignment,
+        };
+        let aligned_end = end.div_ceil(self.alignment) * self.alignment;
+        let request = u32::try_from(aligned_end - start).unwrap_or(u32::MAX);
+
+        let fetched = match read(start, request) {
+            Ok(fetched) => fetched,
+            Err(gst::FlowError::Eos) if kept.is_some() => gst::Buffer::new(),
+            Err(err) => return Err(err),
+        };
+        self.eos = (fetched.size() as u64) < request as u64;
+
+        let window = match kept {
+            Some(window) => {
+                // Drop what was read before `offset` and append the new data,
+                // sharing the memory of both
+                let mut buffer = window
+                    .buffer
+                    .copy_region(gst::BufferCopyFlags::MEMORY, (offset - window.offset) as usize, None)
+                    .map_err(|_| gst::FlowError::Error)?;
+                buffer.append(fetched);
+
+                Window { offset, buffer }
+            }
+            None => Window { offset: start, buffer: fetched },
+        };
+
+        if offset >= window.end() {
+            return Err(gst::FlowError::Eos);
+        }
+
+        let buffer = window.slice(offset, size)?;
+        self.window = Some(window);
+
+        Ok(buffer)
+    }
+}
@@ -0,0 +1,325 @@
+//! Block cache shared between processes through `customsource-cached`.
+//!
+//! The daemon keeps the blocks in slots of a file mapped by all its clients
+//! and serves them on a Unix socket with a line based protocol. On
+//! connection it greets with `HELLO <slots> <path of the blocks file>`,
+//! then:
+//!
+//! ```text
+//! C: GET <index> <file>
+//! S: HIT <slot> <generation> <length>   the block is in that slot
+//! S: FETCH                              the client reads the block from its
+//!                                       backend and answers PUT or ABORT
+//! C: PUT <index> <length> <file>        followed by the block data
+//! S: OK
+//! C: ABORT <index> <file>
+//! S: OK
+//! ```
+//!
+//! `<file>` is `<size> <modification time> <uri>`, see
+//! [`Identity::cache_key()`], so that a replaced file doesn't get the
+//! blocks of the previous one. The daemon only accepts the PUT of a block it
+//! asked that client to fetch, with the length the size of the file gives
+//! for that block, and only serves the processes of its own user.
+//!
+//! A block is only fetched by one client at a time, the others asking for
+//! it wait for its PUT. Every slot starts with a generation counter, odd
+//! while the daemon writes to the slot, so that clients can detect that a
+//! slot was reused while they were copying it.
+
+use super::cache::{self, fetch_block, read_blocks};
+use super::identity::Identity;
+use super::reader::ReadError;
+
+pub use super::cache::BLOCK_SIZE;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Bytes before the data of a slot, holding its generation.
+pub const SLOT_HEADER: usize = 64;
+pub const SLOT_SIZE: usize = SLOT_HEADER + BLOCK_SIZE as usize;
+
+/// The slots of the blocks file mapped in memory.
+#[derive(Debug)]
+pub struct SharedMemory {
+    ptr: *mut u8,
+    slots: usize,
+}
+
+// The memory is only accessed through the generation protocol
+unsafe impl Send for SharedMemory {}
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    /// Maps the `slots` slots of `file`, which must be big enough.
+    pub fn map(file: &File, slots: usize, writable: bool) -> io::Result<Self> {
+        let len = slots * SLOT_SIZE;
+        if file.metadata()?.len() < len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Blocks file is too small"));
+        }
+
+        let prot = match writable {
+            true => libc::PROT_READ | libc::PROT_WRITE,
+            false => libc::PROT_READ,
+        };
+
+        // SAFETY: mapping a file we have open, the mapping is checked below
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(SharedMemory {
+            ptr: ptr as *mut u8,
+            slots,
+        })
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    fn generation(&self, slot: usize) -> &AtomicU64 {
+        assert!(slot < self.slots);
+
+        // SAFETY: in bounds and slots are aligned to their size
+        unsafe { &*(self.ptr.add(slot * SLOT_SIZE) as *const AtomicU64) }
+    }
+
+    /// Copies `len` bytes out of `slot` if it still holds `generation`.
+    pub fn read_slot(&self, slot: usize, generation: u64, len: usize) -> Option<Vec<u8>> {
+        if len > BLOCK_SIZE as usize || self.generation(slot).load(Ordering::Acquire) != generation {
+            return None;
+        }
+
+        let mut data = vec![0; len];
+        // SAFETY: in bounds, torn copies are detected with the generation
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ptr.add(slot * SLOT_SIZE + SLOT_HEADER), data.as_mut_ptr(), len);
+        }
+
+        atomic::fence(Ordering::Acquire);
+        (self.generation(slot).load(Ordering::Relaxed) == generation).then_some(data)
+    }
+
+    /// Writes `data` to `slot` and returns the new generation of the slot.
+    /// Must only be called by the daemon, which is the only writer.
+    pub fn write_slot(&self, slot: usize, data: &[u8]) -> u64 {
+        assert!(data.len() <= BLOCK_SIZE as usize);
+
+        let generation = self.generation(slot);
+        let next = (generation.load(Ordering::Relaxed) | 1) + 1;
+
+        generation.store(next - 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        // SAFETY: in bounds, readers check the generation around their copy
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(slot * SLOT_SIZE + SLOT_HEADER), data.len());
+        }
+        generation.store(next, Ordering::Release);
+
+        next
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `map()` with this length
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.slots * SLOT_SIZE);
+        }
+    }
+}
+
+/// A connection to the daemon.
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Connection {
+    /// Connects to the daemon, returning its greeting.
+    fn open(socket: &Path) -> io::Result<(Self, String)> {
+        let stream = UnixStream::connect(socket)?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        let hello = connection.read_line()?;
+
+        Ok((connection, hello))
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(line.trim_end().to_owned())
+    }
+
+    fn request(&mut self, request: &str, data: &[u8]) -> io::Result<String> {
+        writeln!(self.writer, "{request}")?;
+        self.writer.write_all(data)?;
+
+        self.read_line()
+    }
+
+    fn expect_ok(&mut self, request: &str, data: &[u8]) -> io::Result<()> {
+        match self.request(request, data)?.as_str() {
+            "OK" => Ok(()),
+            reply => Err(invalid_reply(reply)),
+        }
+    }
+}
+
+fn invalid_reply(reply: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid reply from cache daemon: {reply:?}"))
+}
+
+/// Reads the blocks of one file through the daemon.
+///
+/// Every concurrent reader uses its own connection, as a reader asked to
+/// fetch a block keeps its connection until it is fetched. If the daemon
+/// goes away the client keeps working by reading every block from the
+/// backend.
+#[derive(Debug)]
+pub struct Client {
+    socket: PathBuf,
+    file: String,
+    size: u64,
+    shm: SharedMemory,
+    /// Connections not used by a reader.
+    idle: Mutex<Vec<Connection>>,
+    lost: AtomicBool,
+}
+
+impl Client {
+    pub fn connect(socket: &Path, identity: &Identity) -> io::Result<Self> {
+        let (connection, hello) = Connection::open(socket)?;
+        let (slots, path) = hello
+            .strip_prefix("HELLO ")
+            .and_then(|hello| hello.split_once(' '))
+            .and_then(|(slots, path)| Some((slots.parse::<usize>().ok()?, PathBuf::from(path))))
+            .ok_or_else(|| invalid_reply(&hello))?;
+
+        let shm = SharedMemory::map(&File::open(path)?, slots, false)?;
+
+        Ok(Client {
+            socket: socket.to_owned(),
+            file: identity.cache_key(),
+            size: identity.size(),
+            shm,
+            idle: Mutex::new(vec![connection]),
+            lost: AtomicBool::new(false),
+        })
+    }
+
+    /// Takes an idle connection or opens a new one.
+    fn connection(&self) -> Option<Connection> {
+        if self.lost.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        if let Some(connection) = self.idle.lock().unwrap().pop() {
+            return Some(connection);
+        }
+
+        match Connection::open(&self.socket) {
+            Ok((connection, hello)) if hello.starts_with("HELLO ") => Some(connection),
+            _ => {
+                self.lost.store(true, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    pub fn read(
+        &self,
+        offset: u64,
+        size: u32,
+        mut fetch: impl FnMut(u64, u32) -> Result<gst::Buffer, ReadError>,
+        mut lookup: impl FnMut(bool),
+    ) -> Result<gst::Buffer, ReadError> {
+        let mut connection = self.connection();
+
+        let read = read_blocks(offset, size, |index| {
+            let Some(conn) = connection.as_mut() else {
+                lookup(false);
+                return fetch_block(index, &mut fetch);
+            };
+
+            match self.block(conn, index, &mut fetch, &mut lookup) {
+                Ok(block) => block,
+                Err(_) => {
+                    // Lost the daemon, read on our own from now on
+                    connection = None;
+                    self.lost.store(true, Ordering::SeqCst);
+                    lookup(false);
+                    fetch_block(index, &mut fetch)
+                }
+            }
+        });
+
+        if let Some(connection) = connection {
+            self.idle.lock().unwrap().push(connection);
+        }
+
+        read
+    }
+
+    /// Gets the block at `index` from the daemon or fetches it for
+    /// everyone, calling `lookup` with whether it was cached.
+    fn block(
+        &self,
+        conn: &mut Connection,
+        index: u64,
+        fetch: &mut impl FnMut(u64, u32) -> Result<gst::Buffer, ReadError>,
+        lookup: &mut impl FnMut(bool),
+    ) -> io::Result<Result<Arc<[u8]>, ReadError>> {
+        let reply = conn.request(&format!("GET {index} {}", self.file), &[])?;
+
+        if let Some(hit) = reply.strip_prefix("HIT ") {
+            let fields = hit
+                .split(' ')
+                .map(|field| field.parse::<u64>().ok())
+                .collect::<Option<Vec<_>>>()
+                .filter(|fields| fields.len() == 3 && (fields[0] as usize) < self.shm.slots())
+                .ok_or_else(|| invalid_reply(&reply))?;
+
+            let data = self.shm.read_slot(fields[0] as usize, fields[1], fields[2] as usize);
+            lookup(data.is_some());
+
+            return Ok(match data {
+                Some(data) => Ok(Arc::from(data)),
+                // Evicted while we were copying it
+                None => fetch_block(index, fetch),
+            });
+        }
+
+        if reply != "FETCH" {
+            return Err(invalid_reply(&reply));
+        }
+
+        lookup(false);
+
+        let block = fetch_block(index, fetch);
+        match &block {
+            Ok(data) if cache::is_complete(index, data.len(), Some(self.size)) => {
+                conn.expect_ok(&format!("PUT {index} {} {}", data.len(), self.file), data)?;
+            }
+            _ => conn.expect_ok(&format!("ABORT {index} {}", self.file), &[])?,
+        }
+
+        Ok(block)
+    }
+}
@@ -0,0 +1,192 @@
+use gst::glib;
+use gst::prelude::*;
+
+use std::io;
+use std::path::PathBuf;
+
+/// Failures of `CustomSource` that end up as error messages on the bus.
+///
+/// Every message carries a `customsource-error` details structure with the
+/// URI being read and, when known, the offset, size and errno of the failed
+/// operation so applications can tell the user what went wrong.
+#[derive(Debug)]
+pub enum Error {
+    ElementMissing {
+        factory: &'static str,
+    },
+    BackendUnavailable {
+        uri: Option<String>,
+        reason: String,
+    },
+    Io {
+        uri: Option<String>,
+        offset: Option<u64>,
+        err: io::Error,
+    },
+    ShortRead {
+        uri: Option<String>,
+        offset: u64,
+        requested: u32,
+        read: usize,
+    },
+    Timeout {
+        uri: Option<String>,
+        offset: u64,
+        size: u32,
+        timeout: gst::ClockTime,
+    },
+    TraceFile {
+        path: PathBuf,
+        err: io::Error,
+    },
+    MirrorFile {
+        path: PathBuf,
+        err: io::Error,
+    },
+    NotAvailableOffline {
+        uri: Option<String>,
+        reason: String,
+    },
+    Settings {
+        reason: String,
+    },
+    Thread {
+        name: &'static str,
+        err: io::Error,
+    },
+}
+
+impl Error {
+    fn message(&self) -> (glib::Error, String) {
+        match self {
+            Error::ElementMissing { factory } => (
+                glib::Error::new(
+                    gst::CoreError::MissingPlugin,
+                    &format!("Missing element '{factory}', check your GStreamer installation"),
+                ),
+                format!("Could not create '{factory}' element"),
+            ),
+            Error::BackendUnavailable { reason, .. } => (
+                glib::Error::new(gst::ResourceError::OpenRead, "Backend unavailable"),
+                reason.clone(),
+            ),
+            Error::Io { offset, err, .. } => {
+                let domain = match (err.kind(), offset) {
+                    (io::ErrorKind::NotFound, _) => gst::ResourceError::NotFound,
+                    (io::ErrorKind::PermissionDenied, _) => gst::ResourceError::NotAuthorized,
+                    (_, None) => gst::ResourceError::OpenRead,
+                    (_, Some(_)) => gst::ResourceError::Read,
+                };
+
+                let message = match domain {
+                    gst::ResourceError::NotFound => "Resource not found",
+                    gst::ResourceError::NotAuthorized => "Not authorized to access resource",
+                    gst::ResourceError::OpenRead => "Could not open resource for reading",
+                    _ => "Could not read from resource",
+                };
+
+                (glib::Error::new(domain, message), format!("system error: {err}"))
+            }
+            Error::ShortRead {
+                offset,
+                requested,
+                read,
+                ..
+            } => (
+                glib::Error::new(gst::ResourceError::Read, "Could not read from resource"),
+                format!("Short read at offset {offset}: got {read} of {requested} bytes"),
+            ),
+            Error::Timeout {
+                offset,
+                size,
+                timeout,
+                ..
+            } => (
+                glib::Error::new(gst::ResourceError::Read, "Timed out reading from resource"),
+                format!("Reading {size} bytes at offset {offset} timed out after {timeout}"),
+            ),
+            Error::TraceFile { path, err } => (
+                glib::Error::new(gst::ResourceError::OpenWrite, "Could not open trace file for writing"),
+                format!("Could not create {path:?}: {err}"),
+            ),
+            Error::MirrorFile { path, err } => (
+                glib::Error::new(gst::ResourceError::OpenReadWrite, "Could not open mirror file"),
+                format!("Could not open {path:?}: {err}"),
+            ),
+            Error::NotAvailableOffline { reason, .. } => (
+                glib::Error::new(gst::ResourceError::NotFound, "Resource not available offline"),
+                reason.clone(),
+            ),
+            Error::Settings { reason } => (
+                glib::Error::new(gst::LibraryError::Settings, "Invalid settings"),
+                reason.clone(),
+            ),
+            Error::Thread { name, err } => (
+                glib::Error::new(gst::CoreError::Thread, "Could not start thread"),
+                format!("Could not spawn {name} thread: {err}"),
+            ),
+        }
+    }
+
+    fn details(&self) -> gst::Structure {
+        let mut details = gst::Structure::new_empty("customsource-error");
+
+        match self {
+            Error::ElementMissing { factory } => details.set("element", *factory),
+            Error::BackendUnavailable { uri, .. } | Error::NotAvailableOffline { uri, .. } => details.set("uri", uri),
+            Error::Io { uri, offset, err } => {
+                details.set("uri", uri);
+                if let Some(offset) = offset {
+                    details.set("offset", offset);
+                }
+                if let Some(errno) = err.raw_os_error() {
+                    details.set("errno", errno);
+                }
+            }
+            Error::ShortRead {
+                uri,
+                offset,
+                requested,
+                ..
+            } => {
+                details.set("uri", uri);
+                details.set("offset", offset);
+                details.set("size", requested);
+            }
+            Error::Timeout {
+                uri, offset, size, ..
+            } => {
+                details.set("uri", uri);
+                details.set("offset", offset);
+                details.set("size", size);
+            }
+            Error::Settings { .. } => (),
+            Error::Thread { name, err } => {
+                details.set("thread", *name);
+                if let Some(errno) = err.raw_os_error() {
+                    details.set("errno", errno);
+                }
+            }
+            Error::TraceFile { path, err } | Error::MirrorFile { path, err } => {
+                details.set("location", path.to_string_lossy().as_ref());
+                if let Some(errno) = err.raw_os_error() {
+                    details.set("errno", errno);
+                }
+            }
+        }
+
+        details
+    }
+
+    pub fn post(&self, element: &gst::Element) {
+        let (error, debug) = self.message();
+
+        let msg = gst::message::Error::builder_from_error(error)
+            .src(element)
+            .debug(&debug)
+            .details(self.details())
+            .build();
+
+        let _ = element.post_message(msg);
+    }
+}
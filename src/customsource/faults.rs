@@ -0,0 +1,222 @@
+//! Fault injection for testing how downstream elements cope with flaky
+//! storage.
+//!
+//! Faults are described as `GstStructure`s, either separated by `;` in the
+//! `faults` property or one per line in the file given by `fault-scenario`:
+//!
+//! ```text
+//! # Delay reads overlapping the first MiB by 200ms
+//! delay, duration=(guint64)200000000, offset-end=(guint64)1048576
+//! short-read, max-size=(uint)512, probability=(double)0.1
+//! error, offset-start=(guint64)4096, max-hits=(uint)1
+//! bit-flip, count=(uint)4
+//! eos, offset-start=(guint64)10000000
+//! ```
+//!
+//! All faults accept `offset-start` and `offset-end` to only apply to reads
+//! overlapping that range, `probability` to only apply to a fraction of
+//! those reads and `max-hits` to limit how often they trigger.
+//!
+//! Faults apply to what `range()` returns, above the cache and the mirror,
+//! so that corrupted data is never stored and short reads reach downstream
+//! as they are. They still run in the reader, so delays count towards
+//! `read-timeout` and end when flushing.
+
+use super::rng::Rng;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FaultKind {
+    /// Delays the read.
+    Delay(Duration),
+    /// Returns at most `max_size` bytes.
+    ShortRead(usize),
+    /// Fails the read with the given flow return.
+    Error(gst::FlowError),
+    /// Flips `count` random bits of the buffer.
+    BitFlip(u32),
+    /// Returns EOS as if the file ended there.
+    Eos,
+}
+
+#[derive(Debug, Clone)]
+struct Fault {
+    kind: FaultKind,
+    offset_start: u64,
+    offset_end: u64,
+    probability: f64,
+    max_hits: Option<u32>,
+    hits: u32,
+}
+
+impl Fault {
+    fn from_structure(s: &gst::StructureRef) -> Result<Self, String> {
+        let u64_field = |name: &str, default: u64| -> Result<u64, String> {
+            match s.value(name) {
+                Ok(value) => value
+                    .get::<u64>()
+                    .or_else(|_| value.get::<u32>().map(u64::from))
+                    .or_else(|_| value.get::<i32>().map(|value| value.max(0) as u64))
+                    .map_err(|_| format!("Field {name:?} of {:?} must be an integer", s.name())),
+                Err(_) => Ok(default),
+            }
+        };
+
+        let kind = match s.name() {
+            "delay" => FaultKind::Delay(Duration::from_nanos(u64_field("duration", 0)?)),
+            "short-read" => FaultKind::ShortRead(u64_field("max-size", 0)? as usize),
+            "error" => {
+                let flow = match s.get::<&str>("flow").unwrap_or("error") {
+                    "error" => gst::FlowError::Error,
+                    "not-negotiated" => gst::FlowError::NotNegotiated,
+                    "not-linked" => gst::FlowError::NotLinked,
+                    "flushing" => gst::FlowError::Flushing,
+                    "not-supported" => gst::FlowError::NotSupported,
+                    flow => return Err(format!("Unknown flow return {flow:?}")),
+                };
+                FaultKind::Error(flow)
+            }
+            "bit-flip" => FaultKind::BitFlip(u64_field("count", 1)? as u32),
+            "eos" => FaultKind::Eos,
+            name => return Err(format!("Unknown fault {name:?}")),
+        };
+
+        let probability = s.get::<f64>("probability").unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(format!("Probability of {:?} must be between 0 and 1", s.name()));
+        }
+
+        Ok(Fault {
+            kind,
+            offset_start: u64_field("offset-start", 0)?,
+            offset_end: u64_field("offset-end", u64::MAX)?,
+            probability,
+            max_hits: match s.has_field("max-hits") {
+                true => Some(u64_field("max-hits", 0)? as u32),
+                false => None,
+            },
+            hits: 0,
+        })
+    }
+
+    fn applies(&self, offset: u64, size: u32) -> bool {
+        match self.kind {
+            // EOS applies from the start offset to the end of the file
+            FaultKind::Eos => offset + size as u64 > self.offset_start,
+            _ => offset < self.offset_end && offset + size as u64 > self.offset_start,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultInjector {
+    faults: Mutex<Vec<Fault>>,
+    rng: Mutex<Rng>,
+}
+
+impl FaultInjector {
+    /// Parses the faults of the `faults` property and of the scenario file.
+    pub fn new(faults: Option<&str>, scenario: Option<&Path>, seed: u64) -> Result<Self, String> {
+        let mut descriptions = Vec::new();
+
+        if let Some(faults) = faults {
+            descriptions.extend(faults.split(';').map(String::from));
+        }
+
+        if let Some(scenario) = scenario {
+            let contents = std::fs::read_to_string(scenario)
+                .map_err(|err| format!("Could not read fault scenario {scenario:?}: {err}"))?;
+            descriptions.extend(contents.lines().map(String::from));
+        }
+
+        let faults = descriptions
+            .iter()
+            .map(|description| description.trim())
+            .filter(|description| !description.is_empty() && !description.starts_with('#'))
+            .map(|description| {
+                let s = gst::Structure::from_str(description)
+                    .map_err(|_| format!("Invalid fault description {description:?}"))?;
+                Fault::from_structure(&s)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(FaultInjector {
+            faults: Mutex::new(faults),
+            rng: Mutex::new(Rng::new(seed)),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faults.lock().unwrap().is_empty()
+    }
+
+    fn triggered(&self, offset: u64, size: u32) -> Vec<FaultKind> {
+        let mut kinds = Vec::new();
+        let mut rng = self.rng.lock().unwrap();
+
+        for fault in self.faults.lock().unwrap().iter_mut() {
+            if !fault.applies(offset, size) || fault.max_hits.is_some_and(|max| fault.hits >= max) {
+                continue;
+            }
+
+            if fault.probability < 1.0 && rng.next_f64() >= fault.probability {
+                continue;
+            }
+
+            fault.hits += 1;
+            kinds.push(fault.kind);
+        }
+
+        kinds
+    }
+
+    /// Reads through `read`, applying the faults triggered for this read.
+    /// Reads may go through concurrently, e.g. when fetching in parallel.
+    /// Delays wait with `sleep`, which fails if the read is abandoned.
+    pub fn read<E: From<gst::FlowError>>(
+        &self,
+        offset: u64,
+        size: u32,
+        sleep: impl Fn(Duration) -> Result<(), E>,
+        read: impl FnOnce(u64, u32) -> Result<gst::Buffer, E>,
+    ) -> Result<gst::Buffer, E> {
+        let kinds = self.triggered(offset, size);
+
+        for kind in &kinds {
+            match kind {
+                FaultKind::Delay(duration) => sleep(*duration)?,
+                FaultKind::Error(err) => return Err(E::from(*err)),
+                FaultKind::Eos => return Err(E::from(gst::FlowError::Eos)),
+                _ => (),
+            }
+        }
+
+        let mut buffer = read(offset, size)?;
+
+        for kind in &kinds {
+            match kind {
+                FaultKind::ShortRead(max_size) if buffer.size() > *max_size => {
+                    buffer = buffer
+                        .copy_region(gst::BufferCopyFlags::MEMORY, 0, Some(*max_size))
+                        .map_err(|_| gst::FlowError::Error)?;
+                }
+                FaultKind::BitFlip(count) if buffer.size() > 0 => {
+                    let buffer = buffer.make_mut();
+                    let mut map = buffer.map_writable().map_err(|_| gst::FlowError::Error)?;
+                    let mut rng = self.rng.lock().unwrap();
+                    for _ in 0..*count {
+                        let bit = rng.next() as usize % (map.len() * 8);
+                        map[bit / 8] ^= 1 << (bit % 8);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(buffer)
+    }
+}
@@ -0,0 +1,180 @@
+//! Parallel fetching of chunks, for backends with a high latency per
+//! request where a single request at a time can't reach the bandwidth of
+//! the link.
+//!
+//! The file is split in chunks of `chunk_size` bytes fetched by a pool of
+//! `concurrency` threads. A read waits for the chunks it overlaps, which are
+//! fetched in parallel, and when the reads are sequential the following
+//! chunks are fetched ahead too so that the pool stays busy. The chunks are
+//! reassembled in order without copying them.
+
+use std::collections::{btree_map, BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// Reads a range from the backend, from any of the threads of the pool.
+pub type ChunkRead = Arc<dyn Fn(u64, u32) -> Result<gst::Buffer, gst::FlowError> + Send + Sync>;
+
+#[derive(Debug)]
+enum Chunk {
+    Queued,
+    Fetching,
+    Done(Result<gst::Buffer, gst::FlowError>),
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    chunks: BTreeMap<u64, Chunk>,
+    queue: VecDeque<u64>,
+    shutdown: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    inner: Mutex<Inner>,
+    cond: Condvar,
+}
+
+#[derive(Debug)]
+pub struct ChunkFetcher {
+    chunk_size: u64,
+    concurrency: u64,
+    shared: Arc<Shared>,
+    /// End of the previous read, to detect sequential reads.
+    position: Option<u64>,
+}
+
+impl ChunkFetcher {
+    pub fn new(name: &str, chunk_size: u32, concurrency: u32, read: ChunkRead) -> Self {
+        let shared = Arc::new(Shared::default());
+        let chunk_size = chunk_size.max(1) as u64;
+
+        for index in 0..concurrency {
+            let shared = shared.clone();
+            let read = read.clone();
+
+            thread::Builder::new()
+                .name(format!("{name}:fetch{index}"))
+                .spawn(move || ChunkFetcher::run(&shared, chunk_size, &*read))
+                .expect("Failed to spawn fetcher thread");
+        }
+
+        ChunkFetcher {
+            chunk_size,
+            concurrency: concurrency as u64,
+            shared,
+            position: None,
+        }
+    }
+
+    fn run(shared: &Shared, chunk_size: u64, read: &dyn Fn(u64, u32) -> Result<gst::Buffer, gst::FlowError>) {
+        let mut inner = shared.inner.lock().unwrap();
+
+        loop {
+            if inner.shutdown {
+                break;
+            }
+
+            let Some(index) = inner.queue.pop_front() else {
+                inner = shared.cond.wait(inner).unwrap();
+                continue;
+            };
+
+            inner.chunks.insert(index, Chunk::Fetching);
+            drop(inner);
+            let res = read(index * chunk_size, chunk_size as u32);
+            inner = shared.inner.lock().unwrap();
+
+            // Only keep the result if the chunk is still wanted
+            if let Some(chunk @ Chunk::Fetching) = inner.chunks.get_mut(&index) {
+                *chunk = Chunk::Done(res);
+                shared.cond.notify_all();
+            }
+        }
+    }
+
+    /// Reads `size` bytes at `offset` from the chunks, fetching the missing
+    /// ones in parallel.
+    pub fn read(&mut self, offset: u64, size: u32) -> Result<gst::Buffer, gst::FlowError> {
+        let end = offset + (size as u64).max(1);
+        let (first, last) = (offset / self.chunk_size, (end - 1) / self.chunk_size);
+
+        let read_ahead = match self.position == Some(offset) {
+            true => self.concurrency,
+            false => 0,
+        };
+        self.position = Some(end);
+
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        // Forget the chunks outside of the read and the read-ahead, the
+        // ones being fetched are dropped when done
+        let wanted = first..=last + read_ahead;
+        inner.chunks.retain(|index, _| wanted.contains(index));
+        inner.queue.retain(|index| wanted.contains(index));
+
+        let Inner { chunks, queue, .. } = &mut *inner;
+        for index in wanted {
+            if let btree_map::Entry::Vacant(entry) = chunks.entry(index) {
+                entry.insert(Chunk::Queued);
+                queue.push_back(index);
+            }
+        }
+        self.shared.cond.notify_all();
+
+        let mut buffer: Option<gst::Buffer> = None;
+        for index in first..=last {
+            let chunk = loop {
+                if let Some(Chunk::Done(res)) = inner.chunks.get(&index) {
+                    break res.clone();
+                }
+
+                inner = self.shared.cond.wait(inner).unwrap();
+            };
+
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                // The file ends in a previous chunk
+                Err(gst::FlowError::Eos) if buffer.is_some() => break,
+                Err(err) => {
+                    // Fetch it again on the next read
+                    inner.chunks.remove(&index);
+                    return Err(err);
+                }
+            };
+
+            let chunk_offset = index * self.chunk_size;
+            let start = (offset.max(chunk_offset) - chunk_offset) as usize;
+            let chunk_end = ((end - chunk_offset) as usize).min(chunk.size());
+            if start >= chunk_end {
+                break;
+            }
+
+            let part = chunk
+                .copy_region(gst::BufferCopyFlags::MEMORY, start, Some(chunk_end - start))
+                .map_err(|_| gst::FlowError::Error)?;
+            match &mut buffer {
+                Some(buffer) => buffer.append(part),
+                None => buffer = Some(part),
+            }
+
+            // Short chunk, the file ends there
+            if (chunk.size() as u64) < self.chunk_size {
+                break;
+            }
+        }
+
+        buffer.ok_or(gst::FlowError::Eos)
+    }
+}
+
+impl Drop for ChunkFetcher {
+    /// Stops the threads of the pool. They are not joined as they might be
+    /// stuck in a read that never returns.
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.shutdown = true;
+        inner.queue.clear();
+        self.shared.cond.notify_all();
+    }
+}
@@ -0,0 +1,7 @@
+/// FNV-1a, stable across Rust versions unlike `DefaultHasher`, for the
+/// names and ids derived from URIs.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,134 @@
+//! Identity of the file being read, sent downstream so that the decoded
+//! streams can be related to the stored file.
+//!
+//! The stream-id is derived from the canonical URI, the size and the
+//! modification time of the file, so it is the same every time the same
+//! file is read and changes when the file is replaced. The tags carry the
+//! same information for applications.
+
+use super::hash;
+use super::uri;
+
+use std::sync::Once;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+macro_rules! custom_tag {
+    ($name:ident, $tag_name:literal, $type:ty, $nick:literal, $description:literal) => {
+        pub enum $name {}
+
+        impl<'a> gst::tags::Tag<'a> for $name {
+            type TagType = $type;
+
+            fn tag_name<'b>() -> &'b str {
+                $tag_name
+            }
+        }
+
+        impl<'a> gst::tags::CustomTag<'a> for $name {
+            const FLAG: gst::TagFlag = gst::TagFlag::Meta;
+            const NICK: &'static str = $nick;
+            const DESCRIPTION: &'static str = $description;
+        }
+    };
+}
+
+custom_tag!(FileName, "customsource-file-name", String, "file name", "Name of the file read");
+custom_tag!(FileSize, "customsource-file-size", u64, "file size", "Size in bytes of the file read");
+custom_tag!(FileModified, "customsource-file-modified", gst::DateTime, "file modified", "Modification time of the file read");
+custom_tag!(Backend, "customsource-backend", String, "backend", "Backend the file is read with");
+
+/// Registers the tags describing the file, once per process.
+pub fn register_tags() {
+    static REGISTER: Once = Once::new();
+
+    REGISTER.call_once(|| {
+        gst::tags::register::<FileName>();
+        gst::tags::register::<FileSize>();
+        gst::tags::register::<FileModified>();
+        gst::tags::register::<Backend>();
+    });
+}
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// Canonical URI of the file.
+    uri: String,
+    file_name: Option<String>,
+    size: u64,
+    /// Unknown when reading offline.
+    modified: Option<SystemTime>,
+    backend: &'static str,
+}
+
+impl Identity {
+    pub fn new(uri: String, size: u64, modified: Option<SystemTime>, backend: &'static str) -> Self {
+        let file_name = uri::uri_to_path(&uri)
+            .ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
+
+        Identity {
+            uri,
+            file_name,
+            size,
+            modified,
+            backend,
+        }
+    }
+
+    fn modified_secs(&self) -> Option<i64> {
+        let modified = self.modified?.duration_since(UNIX_EPOCH).ok()?;
+
+        i64::try_from(modified.as_secs()).ok()
+    }
+
+    /// Modification time with nanoseconds since the epoch.
+    fn modified_string(&self) -> Option<String> {
+        let modified = self.modified?.duration_since(UNIX_EPOCH).ok()?;
+
+        Some(format!("{}.{:09}", modified.as_secs(), modified.subsec_nanos()))
+    }
+
+    pub fn stream_id(&self) -> String {
+        let mut key = format!("{}\n{}", self.uri, self.size);
+        if let Some(modified) = self.modified_string() {
+            key.push_str(&format!("\n{modified}"));
+        }
+
+        format!("{:016x}", hash::fnv1a(key.as_bytes()))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Names this version of the file for the cache daemon: its size,
+    /// modification time and URI separated by spaces, so that the blocks of
+    /// a replaced file are not served anymore.
+    pub fn cache_key(&self) -> String {
+        let modified = self.modified_string().unwrap_or_else(|| String::from("-"));
+
+        format!("{} {modified} {}", self.size, self.uri)
+    }
+
+    /// Global tags describing the file.
+    pub fn tags(&self) -> gst::TagList {
+        let mut tags = gst::TagList::new();
+
+        {
+            let tags = tags.get_mut().unwrap();
+            tags.set_scope(gst::TagScope::Global);
+
+            tags.add::<gst::tags::Location>(&self.uri.as_str(), gst::TagMergeMode::Replace);
+            if let Some(file_name) = &self.file_name {
+                tags.add::<FileName>(file_name, gst::TagMergeMode::Replace);
+            }
+            tags.add::<FileSize>(&self.size, gst::TagMergeMode::Replace);
+            if let Some(modified) = self.modified_secs().and_then(|secs| gst::DateTime::from_unix_epoch_utc(secs).ok()) {
+                tags.add::<FileModified>(&modified, gst::TagMergeMode::Replace);
+            }
+            tags.add::<Backend>(&self.backend.to_string(), gst::TagMergeMode::Replace);
+        }
+
+        tags
+    }
+}
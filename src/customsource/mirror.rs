@@ -0,0 +1,166 @@
+//! Progressive mirroring of the file being read to a local sparse file.
+//!
+//! Every range read from the backend is written at the same offset of the
+//! mirror and the ranges already mirrored are tracked in `<mirror>.ranges`,
+//! a text file with the size of the file on the first line and one
+//! `<start> <end>` range per line after it, so the mirror is reused by the
+//! next runs. Reads of mirrored ranges, and all reads once the mirror is
+//! complete, are served from the local file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// Sorted, disjoint and non-adjacent `[start, end)` ranges.
+#[derive(Debug, Default)]
+struct Ranges(Vec<(u64, u64)>);
+
+impl Ranges {
+    fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+
+        // Ranges overlapping or touching the new one are merged into it
+        let first = self.0.partition_point(|range| range.1 < start);
+        let last = self.0.partition_point(|range| range.0 <= end);
+
+        let merged = match self.0[first..last] {
+            [] => (start, end),
+            [.., (_, last_end)] => (start.min(self.0[first].0), end.max(last_end)),
+        };
+
+        self.0.splice(first..last, [merged]);
+    }
+
+    fn contains(&self, start: u64, end: u64) -> bool {
+        let index = self.0.partition_point(|range| range.1 < end);
+
+        self.0.get(index).is_some_and(|range| range.0 <= start && end <= range.1)
+    }
+}
+
+#[derive(Debug)]
+pub struct Mirror {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    ranges: Ranges,
+}
+
+impl Mirror {
+    /// Opens the mirror at `path` of a file of `size` bytes, reusing what
+    /// was mirrored by previous runs if it was for a file of the same size.
+    pub fn open(path: &Path, size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        let ranges = match Mirror::load_ranges(&Mirror::ranges_path(path)) {
+            Ok(Some((ranges_size, ranges))) if ranges_size == size && file.metadata()?.len() == size => ranges,
+            _ => Ranges::default(),
+        };
+
+        // Sparse, only the ranges written take space on disk
+        file.set_len(size)?;
+
+        Ok(Mirror {
+            path: path.to_path_buf(),
+            file,
+            size,
+            ranges,
+        })
+    }
+
+    /// Size of the file mirrored at `path` if the mirror is complete.
+    pub fn complete_size(path: &Path) -> Option<u64> {
+        let (size, ranges) = Mirror::load_ranges(&Mirror::ranges_path(path)).ok()??;
+        let len = std::fs::metadata(path).ok()?.len();
+
+        (len == size && (size == 0 || ranges.contains(0, size))).then_some(size)
+    }
+
+    fn ranges_path(path: &Path) -> PathBuf {
+        let mut ranges_path = path.to_path_buf().into_os_string();
+        ranges_path.push(".ranges");
+
+        PathBuf::from(ranges_path)
+    }
+
+    fn load_ranges(path: &Path) -> io::Result<Option<(u64, Ranges)>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let Some(size) = lines.next().transpose()?.and_then(|line| line.trim().parse::<u64>().ok()) else {
+            return Ok(None);
+        };
+
+        let mut ranges = Ranges::default();
+        for line in lines {
+            let line = line?;
+            let Some((start, end)) = line.trim().split_once(' ') else {
+                return Ok(None);
+            };
+
+            match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if end <= size => ranges.insert(start, end),
+                _ => return Ok(None),
+            }
+        }
+
+        Ok(Some((size, ranges)))
+    }
+
+    /// Writes the mirrored ranges to `<mirror>.ranges`, after syncing the
+    /// data so the index never claims data that is not on disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()?;
+
+        let mut writer = BufWriter::new(File::create(Mirror::ranges_path(&self.path))?);
+        writeln!(writer, "{}", self.size)?;
+        for (start, end) in &self.ranges.0 {
+            writeln!(writer, "{start} {end}")?;
+        }
+
+        writer.flush()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sorted `[start, end)` ranges already mirrored.
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges.0
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.size == 0 || self.ranges.contains(0, self.size)
+    }
+
+    /// Reads `size` bytes at `offset` if they are all mirrored, truncated at
+    /// the end of the file.
+    pub fn read(&self, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+        let end = (offset + size as u64).min(self.size);
+        if offset >= end || !self.ranges.contains(offset, end) {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; (end - offset) as usize];
+        self.file.read_exact_at(&mut data, offset)?;
+
+        Ok(Some(gst::Buffer::from_mut_slice(data)))
+    }
+
+    /// Mirrors `data` read at `offset`, returns whether this completed the
+    /// mirror.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<bool> {
+        let end = (offset + data.len() as u64).min(self.size);
+        if offset >= end || self.ranges.contains(offset, end) {
+            return Ok(false);
+        }
+
+        self.file.write_all_at(&data[..(end - offset) as usize], offset)?;
+        self.ranges.insert(offset, end);
+
+        Ok(self.is_complete())
+    }
+}
@@ -0,0 +1,304 @@
+//! Local file backend reading through a memory mapping of the file.
+//!
+//! Buffers wrap the mapped pages instead of copying them. If the file is
+//! truncated while mapped, touching the pages past its new end raises
+//! SIGBUS: the handler installed here maps a zeroed page over the faulting
+//! one, with the raw system call as the libc wrapper is not
+//! async-signal-safe, so that the buffers already handed out read zeros
+//! instead of crashing the process, and the reads following the fault fail.
+//! The file is read with `pread()` from then on. The size of the file is
+//! only checked again when reading past the end of the mapping, to map it
+//! again if it grew.
+
+use super::backend;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+
+use once_cell::sync::OnceCell;
+
+const MAX_MAPPINGS: usize = 64;
+
+/// A mapping the SIGBUS handler may repair. Only atomics are used as the
+/// handler can interrupt any code, `start` is 0 for an unused slot.
+struct MappingSlot {
+    start: AtomicUsize,
+    end: AtomicUsize,
+    truncated: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_SLOT: MappingSlot = MappingSlot {
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    truncated: AtomicBool::new(false),
+};
+
+static MAPPINGS: [MappingSlot; MAX_MAPPINGS] = [UNUSED_SLOT; MAX_MAPPINGS];
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(4096);
+static PREVIOUS_HANDLER: OnceCell<libc::sigaction> = OnceCell::new();
+static INSTALL_HANDLER: Once = Once::new();
+
+extern "C" fn handle_sigbus(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    // SAFETY: the kernel passes a valid siginfo with SA_SIGINFO
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+
+    for slot in &MAPPINGS {
+        let start = slot.start.load(Ordering::SeqCst);
+        if start == 0 || addr < start || addr >= slot.end.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        // SAFETY: replaces a page of our own mapping, keeping the errno of
+        // the interrupted code
+        let page = unsafe {
+            let errno = *libc::__errno_location();
+            let page = libc::syscall(
+                libc::SYS_mmap,
+                addr & !(page_size - 1),
+                page_size,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1 as libc::c_int,
+                0 as libc::off_t,
+            );
+            *libc::__errno_location() = errno;
+
+            page
+        };
+
+        if page != -1 {
+            slot.truncated.store(true, Ordering::SeqCst);
+            return;
+        }
+    }
+
+    // Not one of our mappings, let the previous handler deal with it
+    let Some(previous) = PREVIOUS_HANDLER.get() else {
+        return;
+    };
+
+    // SAFETY: calling or restoring the handler that was installed before ours
+    unsafe {
+        match previous.sa_sigaction {
+            libc::SIG_DFL | libc::SIG_IGN => {
+                // The faulting access is retried and gets the default action
+                libc::sigaction(signal, previous, std::ptr::null_mut());
+            }
+            handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                    std::mem::transmute(handler);
+                handler(signal, info, context);
+            }
+            handler => {
+                let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+                handler(signal);
+            }
+        }
+    }
+}
+
+fn install_handler() {
+    INSTALL_HANDLER.call_once(|| {
+        // SAFETY: plain libc calls with initialized structures
+        unsafe {
+            let page_size = libc::sysconf(libc::_SC_PAGESIZE);
+            if page_size > 0 {
+                PAGE_SIZE.store(page_size as usize, Ordering::Relaxed);
+            }
+
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_sigbus as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(libc::SIGBUS, &action, &mut previous) == 0 {
+                let _ = PREVIOUS_HANDLER.set(previous);
+            }
+        }
+    });
+}
+
+/// A read-only mapping of a whole file.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    slot: &'static MappingSlot,
+}
+
+// The mapping is read-only
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Can't map an empty file"));
+        }
+
+        install_handler();
+
+        // SAFETY: mapping a file we have open, the result is checked
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let start = ptr as usize;
+        let Some(slot) = MAPPINGS.iter().find(|slot| {
+            slot.start.compare_exchange(0, start, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        }) else {
+            // SAFETY: unmapping what was just mapped
+            unsafe { libc::munmap(ptr, len) };
+            return Err(io::Error::other(format!("Too many files mapped, at most {MAX_MAPPINGS}")));
+        };
+
+        slot.truncated.store(false, Ordering::SeqCst);
+        slot.end.store(start + len, Ordering::SeqCst);
+
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len,
+            slot,
+        })
+    }
+
+    /// Whether touching the pages raised SIGBUS, the file was truncated.
+    fn is_truncated(&self) -> bool {
+        self.slot.truncated.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        self.slot.end.store(0, Ordering::SeqCst);
+        self.slot.start.store(0, Ordering::SeqCst);
+
+        // SAFETY: unmapping our own mapping, no buffer references it anymore
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// Part of a mapping, to wrap it in a `gst::Memory` without copying.
+struct MappedSlice {
+    mapping: Arc<Mapping>,
+    start: usize,
+    end: usize,
+}
+
+impl AsRef<[u8]> for MappedSlice {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: in bounds of the mapping kept alive by the slice
+        unsafe { std::slice::from_raw_parts(self.mapping.ptr.add(self.start), self.end - self.start) }
+    }
+}
+
+pub struct MappedFile {
+    file: Arc<File>,
+    mapping: Mutex<Option<Arc<Mapping>>>,
+    /// Why the file is read with `pread()` from the start.
+    map_error: Option<io::Error>,
+}
+
+impl MappedFile {
+    /// Maps the open `file`, files that can't be mapped are read with
+    /// `pread()`.
+    pub fn new(file: Arc<File>) -> io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+
+        let (mapping, map_error) = match Mapping::new(&file, len) {
+            Ok(mapping) => (Some(Arc::new(mapping)), None),
+            Err(err) => (None, Some(err)),
+        };
+
+        Ok(MappedFile {
+            file,
+            mapping: Mutex::new(mapping),
+            map_error,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn map_error(&self) -> Option<&io::Error> {
+        self.map_error.as_ref()
+    }
+
+    /// Whether the reads are served from the mapping, without system calls.
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.lock().unwrap().is_some()
+    }
+
+    /// Reads `size` bytes at `offset`, `None` at the end of the file. Fails
+    /// once the file was found truncated since it was mapped.
+    pub fn read(&self, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+        let mut mapping = self.mapping.lock().unwrap();
+        let Some(mut current) = mapping.clone() else {
+            drop(mapping);
+            return self.pread(offset, size);
+        };
+
+        let end = offset.saturating_add(size as u64);
+        if end > current.len as u64 && !current.is_truncated() {
+            current = match self.refresh(&current)? {
+                Some(refreshed) => {
+                    *mapping = Some(refreshed.clone());
+                    refreshed
+                }
+                None => current,
+            };
+        }
+
+        // The mapping can't be trusted anymore
+        if current.is_truncated() {
+            *mapping = None;
+
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("File truncated below {} bytes while mapped", current.len),
+            ));
+        }
+        drop(mapping);
+
+        if offset >= current.len as u64 {
+            return Ok(None);
+        }
+
+        let (start, end) = (offset as usize, end.min(current.len as u64) as usize);
+
+        Ok(Some(gst::Buffer::from_slice(MappedSlice {
+            mapping: current,
+            start,
+            end,
+        })))
+    }
+
+    /// Maps the file again if it grew past `mapping`, for the reads past its
+    /// end. A file smaller than the mapping was truncated.
+    fn refresh(&self, mapping: &Mapping) -> io::Result<Option<Arc<Mapping>>> {
+        let len = self.file.metadata()?.len() as usize;
+
+        if len < mapping.len {
+            mapping.slot.truncated.store(true, Ordering::SeqCst);
+            return Ok(None);
+        }
+        if len == mapping.len {
+            return Ok(None);
+        }
+
+        Mapping::new(&self.file, len).map(|mapping| Some(Arc::new(mapping)))
+    }
+
+    fn pread(&self, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+        backend::pread(&self.file, offset, size)
+    }
+}
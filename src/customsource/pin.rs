@@ -0,0 +1,101 @@
+//! Pinning of files for offline use.
+//!
+//! A pinned file is a complete mirror (see `mirror`) stored in the pin
+//! directory under a name derived from its URI. With `pin-directory` set,
+//! `CustomSource` mirrors the file it reads to its pinned location unless
+//! `mirror-location` is set, and with `offline` it only reads from there.
+
+use gst::glib;
+use gst::prelude::*;
+
+use super::hash;
+use super::mirror::Mirror;
+use super::uri;
+
+use std::path::{Path, PathBuf};
+
+/// Size of the reads done when pinning a file.
+const PIN_READ_SIZE: u32 = 1024 * 1024;
+
+/// Where the file at `uri` is pinned in `directory`.
+pub fn pinned_path(directory: &Path, uri: &str) -> PathBuf {
+    directory.join(format!("{:016x}", hash::fnv1a(uri.as_bytes())))
+}
+
+/// Normalizes `uri` the way `CustomSource` does for its location.
+fn normalize_uri(uri: &str) -> Result<String, glib::Error> {
+    let path = uri::uri_to_path(uri)?;
+
+    uri::path_to_uri(&path, None).map(String::from).ok_or_else(|| {
+        glib::Error::new(gst::URIError::BadUri, &format!("Could not build an URI for {path:?}"))
+    })
+}
+
+/// Whether `uri` is completely pinned in `directory`.
+pub fn is_pinned(directory: &Path, uri: &str) -> Result<bool, glib::Error> {
+    let path = pinned_path(directory, &normalize_uri(uri)?);
+
+    Ok(Mirror::complete_size(&path).is_some())
+}
+
+/// Reads the whole file at `uri` through a `CustomSource` so that it gets
+/// pinned in `directory`. `progress` is called after every read with the
+/// number of bytes pinned so far and the size of the file.
+pub fn pin(directory: &Path, uri: &str, mut progress: impl FnMut(u64, u64)) -> Result<(), glib::Error> {
+    let uri = normalize_uri(uri)?;
+    if let Some(size) = Mirror::complete_size(&pinned_path(directory, &uri)) {
+        progress(size, size);
+        return Ok(());
+    }
+
+    let source = gst::ElementFactory::make("customsource")
+        .property("pin-directory", directory.to_string_lossy().as_ref())
+        .build()
+        .map_err(|err| glib::Error::new(gst::CoreError::MissingPlugin, &err.message))?;
+    source.dynamic_cast_ref::<gst::URIHandler>().unwrap().set_uri(&uri)?;
+
+    // A pipeline to collect the error messages of the source
+    let pipeline = gst::Pipeline::new(None);
+    pipeline.add(&source).unwrap();
+
+    let bus = pipeline.bus().unwrap();
+    let bus_error = |fallback: glib::Error| {
+        bus.iter()
+            .find_map(|msg| match msg.view() {
+                gst::MessageView::Error(err) => Some(err.error()),
+                _ => None,
+            })
+            .unwrap_or(fallback)
+    };
+
+    let res = (|| {
+        pipeline.set_state(gst::State::Paused).map_err(|_| {
+            bus_error(glib::Error::new(gst::CoreError::StateChange, "Could not start reading"))
+        })?;
+
+        let srcpad = source.static_pad("src").unwrap();
+        let size = srcpad.query_duration::<gst::format::Bytes>().map_or(0, |size| *size);
+
+        let mut offset = 0;
+        loop {
+            match srcpad.range(offset, PIN_READ_SIZE) {
+                Ok(buffer) => offset += buffer.size() as u64,
+                Err(gst::FlowError::Eos) => break,
+                Err(err) => {
+                    return Err(bus_error(glib::Error::new(
+                        gst::ResourceError::Read,
+                        &format!("Could not read at offset {offset}: {err:?}"),
+                    )))
+                }
+            }
+
+            progress(offset, size.max(offset));
+        }
+
+        Ok(())
+    })();
+
+    let _ = pipeline.set_state(gst::State::Null);
+
+    res
+}
@@ -0,0 +1,271 @@
+//! Prefetching of ranges ahead of the reads, e.g. around the position of a
+//! seek or the ranges given by `prefetch-range` events.
+//!
+//! The ranges are read from a dedicated thread and kept until `max_size`
+//! bytes are stored, the oldest being dropped first. Reads falling in a
+//! prefetched range are served from it without going to the backend.
+//!
+//! The progress of the range being prefetched is reported after every read
+//! so that the source can tell how far ahead of the reads it is.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// Size of the reads done to prefetch a range.
+const PREFETCH_READ_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+struct Prefetched {
+    offset: u64,
+    buffer: gst::Buffer,
+    /// Whether the file ends with this buffer.
+    eos: bool,
+}
+
+impl Prefetched {
+    fn end(&self) -> u64 {
+        self.offset + self.buffer.size() as u64
+    }
+}
+
+/// Progress of the prefetching of a `[start, end)` range.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub start: u64,
+    pub end: u64,
+    /// End of the data prefetched contiguously from `start`.
+    pub filled: u64,
+    /// Rate of the reads of the range so far, in bytes per second.
+    pub rate: Option<u64>,
+    /// Whether nothing more is prefetched for the range, because it is
+    /// complete, the file ended, it was replaced or a read failed.
+    pub done: bool,
+}
+
+impl Progress {
+    pub fn percent(&self) -> i32 {
+        if self.done || self.end <= self.start {
+            return 100;
+        }
+
+        ((self.filled - self.start) * 100 / (self.end - self.start)) as i32
+    }
+
+    /// Whether a read at `offset` is in the range but past what was
+    /// prefetched, i.e. the reads caught up with the prefetching.
+    pub fn is_behind(&self, offset: u64) -> bool {
+        !self.done && self.filled <= offset && offset < self.end
+    }
+
+    /// Estimated time left to fill the range, in milliseconds.
+    pub fn time_left(&self) -> Option<u64> {
+        let rate = self.rate.filter(|rate| *rate > 0)?;
+
+        Some((self.end - self.filled).saturating_mul(1000) / rate)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// `[start, end)` ranges to prefetch.
+    queue: VecDeque<(u64, u64)>,
+    /// Incremented when the queue is replaced, to abort the range being
+    /// prefetched.
+    generation: u64,
+    prefetched: VecDeque<Prefetched>,
+    size: usize,
+    /// Progress of the last range prefetched.
+    progress: Option<Progress>,
+    shutdown: bool,
+}
+
+impl Inner {
+    fn find(&self, offset: u64) -> Option<&Prefetched> {
+        self.prefetched.iter().find(|prefetched| prefetched.offset <= offset && offset < prefetched.end())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    inner: Mutex<Inner>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Clone)]
+pub struct Prefetcher {
+    shared: Arc<Shared>,
+    max_size: u64,
+}
+
+impl Prefetcher {
+    /// `progress` is called from the prefetch thread before every read and
+    /// once a range is done.
+    pub fn new<F, P>(name: &str, max_size: u64, read: F, progress: P) -> Self
+    where
+        F: FnMut(u64, u32) -> Result<gst::Buffer, gst::FlowError> + Send + 'static,
+        P: FnMut(Progress) + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+
+        let worker_shared = shared.clone();
+        thread::Builder::new()
+            .name(format!("{name}:prefetch"))
+            .spawn(move || Prefetcher::run(&worker_shared, max_size as usize, read, progress))
+            .expect("Failed to spawn prefetch thread");
+
+        Prefetcher { shared, max_size }
+    }
+
+    fn run<F, P>(shared: &Shared, max_size: usize, mut read: F, mut on_progress: P)
+    where
+        F: FnMut(u64, u32) -> Result<gst::Buffer, gst::FlowError>,
+        P: FnMut(Progress),
+    {
+        let mut inner = shared.inner.lock().unwrap();
+
+        loop {
+            if inner.shutdown {
+                break;
+            }
+
+            let Some((start, end)) = inner.queue.pop_front() else {
+                inner = shared.cond.wait(inner).unwrap();
+                continue;
+            };
+
+            let generation = inner.generation;
+            let started = Instant::now();
+            let mut read_bytes = 0;
+            let mut progress = Progress {
+                start,
+                end,
+                filled: start,
+                rate: None,
+                done: false,
+            };
+
+            let mut offset = start;
+            while offset < end && !inner.shutdown && inner.generation == generation {
+                if let Some(prefetched) = inner.find(offset) {
+                    if prefetched.eos {
+                        break;
+                    }
+
+                    offset = prefetched.end();
+                    continue;
+                }
+
+                progress.filled = offset;
+                inner.progress = Some(progress);
+
+                // Whole reads even at the end of the range so that the end of
+                // the file is noticed
+                let size = PREFETCH_READ_SIZE;
+                drop(inner);
+                on_progress(progress);
+                let res = read(offset, size as u32);
+                inner = shared.inner.lock().unwrap();
+
+                let Ok(buffer) = res else {
+                    break;
+                };
+
+                let len = buffer.size() as u64;
+                let eos = len < size;
+                read_bytes += len;
+                let elapsed = started.elapsed().as_millis().max(1) as u64;
+                progress.rate = Some(read_bytes * 1000 / elapsed);
+
+                inner.size += buffer.size();
+                inner.prefetched.push_back(Prefetched { offset, buffer, eos });
+
+                while inner.size > max_size {
+                    let Some(oldest) = inner.prefetched.pop_front() else {
+                        break;
+                    };
+                    inner.size -= oldest.buffer.size();
+                }
+
+                if eos || len == 0 {
+                    break;
+                }
+                offset += len;
+            }
+
+            progress.filled = offset.clamp(start, end);
+            progress.done = true;
+            inner.progress = Some(progress);
+
+            drop(inner);
+            on_progress(progress);
+            inner = shared.inner.lock().unwrap();
+        }
+    }
+
+    /// Queues `size` bytes at `offset` for prefetching, dropping the ranges
+    /// queued before if `replace`.
+    pub fn request(&self, offset: u64, size: u64, replace: bool) {
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        if replace {
+            inner.queue.clear();
+            inner.generation += 1;
+        }
+
+        let size = size.min(self.max_size);
+        inner.queue.push_back((offset, offset.saturating_add(size)));
+        self.shared.cond.notify_all();
+    }
+
+    /// `size` bytes at `offset` if they were all prefetched, truncated at
+    /// the end of the file.
+    pub fn lookup(&self, offset: u64, size: u32) -> Option<gst::Buffer> {
+        let inner = self.shared.inner.lock().unwrap();
+        let end = offset + size as u64;
+
+        let mut buffer: Option<gst::Buffer> = None;
+        let mut position = offset;
+        while position < end {
+            let prefetched = inner.find(position)?;
+
+            let start = (position - prefetched.offset) as usize;
+            let len = (end.min(prefetched.end()) - position) as usize;
+            let part = prefetched.buffer.copy_region(gst::BufferCopyFlags::MEMORY, start, Some(len)).ok()?;
+            match &mut buffer {
+                Some(buffer) => buffer.append(part),
+                None => buffer = Some(part),
+            }
+
+            position += len as u64;
+            if prefetched.eos && position == prefetched.end() {
+                break;
+            }
+        }
+
+        buffer
+    }
+
+    /// Progress of the range being prefetched, or of the last one.
+    pub fn progress(&self) -> Option<Progress> {
+        self.shared.inner.lock().unwrap().progress
+    }
+
+    /// `[start, end)` ranges of the data prefetched, unsorted.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        let inner = self.shared.inner.lock().unwrap();
+
+        inner.prefetched.iter().map(|prefetched| (prefetched.offset, prefetched.end())).collect()
+    }
+
+    /// Stops the prefetch thread. It is not joined as it might be stuck in
+    /// a read that never returns.
+    pub fn shutdown(&self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.shutdown = true;
+        inner.queue.clear();
+        inner.prefetched.clear();
+        self.shared.cond.notify_all();
+    }
+}
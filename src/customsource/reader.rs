@@ -0,0 +1,232 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum ReadError {
+    Flow(gst::FlowError),
+    Timeout,
+    Cancelled,
+}
+
+impl From<gst::FlowError> for ReadError {
+    fn from(err: gst::FlowError) -> Self {
+        ReadError::Flow(err)
+    }
+}
+
+struct Request {
+    seqnum: u64,
+    offset: u64,
+    size: u32,
+}
+
+#[derive(Default)]
+struct Inner {
+    flushing: bool,
+    shutdown: bool,
+    seqnum: u64,
+    /// The request the caller is still waiting for.
+    waiting: Option<u64>,
+    request: Option<Request>,
+    result: Option<(u64, Result<gst::Buffer, ReadError>)>,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    cond: Condvar,
+}
+
+/// Given to the reads to wait in a way that ends as soon as nobody waits
+/// for them anymore.
+pub struct Interrupt<'a> {
+    shared: &'a Shared,
+    /// The request being read by the worker, `None` on the calling thread.
+    seqnum: Option<u64>,
+}
+
+impl Interrupt<'_> {
+    /// Waits for `duration`, or fails with `ReadError::Cancelled` once the
+    /// reader is set flushing or the caller timed out.
+    pub fn sleep(&self, duration: Duration) -> Result<(), ReadError> {
+        let deadline = Instant::now() + duration;
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        loop {
+            if inner.flushing || self.seqnum.is_some_and(|seqnum| inner.waiting != Some(seqnum)) {
+                return Err(ReadError::Cancelled);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+
+            inner = self.shared.cond.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+    }
+}
+
+type ReadFn = dyn FnMut(u64, u32, &Interrupt) -> Result<gst::Buffer, ReadError> + Send;
+
+/// Performs the reads of the backend from a dedicated thread, or directly
+/// on the calling thread when they can't time out.
+///
+/// A read blocked in the kernel (e.g. on a stuck NFS mount) cannot be
+/// interrupted, so instead the streaming thread stops waiting for it when
+/// the timeout expires or when the reader is set flushing. The abandoned
+/// read keeps the worker busy until it returns and its result is dropped,
+/// so it must not hold any lock the rest of the element needs.
+#[derive(Clone)]
+pub struct Reader {
+    shared: Arc<Shared>,
+    /// The reads done on the calling thread, without worker.
+    direct: Option<Arc<Mutex<Box<ReadFn>>>>,
+}
+
+impl Reader {
+    /// Reads through `read` from a worker thread if `threaded`, which
+    /// reads with a timeout need.
+    pub fn new<F>(name: &str, threaded: bool, read: F) -> io::Result<Self>
+    where
+        F: FnMut(u64, u32, &Interrupt) -> Result<gst::Buffer, ReadError> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner::default()),
+            cond: Condvar::new(),
+        });
+
+        if !threaded {
+            let read: Box<ReadFn> = Box::new(read);
+
+            return Ok(Self {
+                shared,
+                direct: Some(Arc::new(Mutex::new(read))),
+            });
+        }
+
+        let worker_shared = shared.clone();
+        thread::Builder::new()
+            .name(format!("{name}:reader"))
+            .spawn(move || Reader::run(&worker_shared, read))?;
+
+        Ok(Self { shared, direct: None })
+    }
+
+    fn run<F>(shared: &Shared, mut read: F)
+    where
+        F: FnMut(u64, u32, &Interrupt) -> Result<gst::Buffer, ReadError>,
+    {
+        let mut inner = shared.inner.lock().unwrap();
+
+        loop {
+            if inner.shutdown {
+                break;
+            }
+
+            let Some(request) = inner.request.take() else {
+                inner = shared.cond.wait(inner).unwrap();
+                continue;
+            };
+
+            drop(inner);
+            let interrupt = Interrupt {
+                shared,
+                seqnum: Some(request.seqnum),
+            };
+            let res = read(request.offset, request.size, &interrupt);
+            inner = shared.inner.lock().unwrap();
+
+            // Only keep the result if the caller is still waiting for it
+            if inner.waiting == Some(request.seqnum) && !inner.flushing {
+                inner.result = Some((request.seqnum, res));
+                shared.cond.notify_all();
+            }
+        }
+    }
+
+    pub fn read(
+        &self,
+        offset: u64,
+        size: u32,
+        timeout: Option<gst::ClockTime>,
+    ) -> Result<gst::Buffer, ReadError> {
+        let deadline = timeout.map(|timeout| Instant::now() + Duration::from(timeout));
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        if inner.flushing {
+            return Err(ReadError::Cancelled);
+        }
+
+        if let Some(read) = &self.direct {
+            drop(inner);
+
+            let interrupt = Interrupt {
+                shared: &self.shared,
+                seqnum: None,
+            };
+
+            return read.lock().unwrap()(offset, size, &interrupt);
+        }
+
+        inner.seqnum += 1;
+        let seqnum = inner.seqnum;
+        inner.result = None;
+        inner.waiting = Some(seqnum);
+        inner.request = Some(Request {
+            seqnum,
+            offset,
+            size,
+        });
+        self.shared.cond.notify_all();
+
+        let res = loop {
+            if inner.flushing {
+                break Err(ReadError::Cancelled);
+            }
+
+            if let Some((result_seqnum, res)) = inner.result.take() {
+                if result_seqnum == seqnum {
+                    break res;
+                }
+            }
+
+            inner = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Err(ReadError::Timeout);
+                    }
+
+                    self.shared.cond.wait_timeout(inner, deadline - now).unwrap().0
+                }
+                None => self.shared.cond.wait(inner).unwrap(),
+            };
+        };
+
+        // Wakes up the read if it is only waiting
+        inner.request = None;
+        inner.waiting = None;
+        self.shared.cond.notify_all();
+
+        res
+    }
+
+    /// Makes the pending and all following reads fail with
+    /// `ReadError::Cancelled` until flushing is unset again.
+    pub fn set_flushing(&self, flushing: bool) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.flushing = flushing;
+        self.shared.cond.notify_all();
+    }
+
+    /// Stops the worker thread. It is not joined as it might be stuck
+    /// in a read that never returns.
+    pub fn shutdown(&self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.flushing = true;
+        inner.shutdown = true;
+        self.shared.cond.notify_all();
+    }
+}
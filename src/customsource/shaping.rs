@@ -0,0 +1,109 @@
+//! Bandwidth and latency shaping of the reads, to emulate slow storage
+//! such as network mounts over a VPN.
+
+use once_cell::sync::Lazy;
+
+use super::rng::Rng;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Link shared by all the instances with `shaping-shared` enabled so that
+/// together they don't exceed the bandwidth of a single link.
+static SHARED_LINK: Lazy<Arc<Mutex<Link>>> = Lazy::new(|| Arc::new(Mutex::new(Link::default())));
+
+/// Tracks when the link is busy transferring previously read data.
+#[derive(Debug, Default)]
+struct Link {
+    busy_until: Option<Instant>,
+}
+
+impl Link {
+    /// Reserves the link for transferring `bytes` at `bitrate` and returns
+    /// when the transfer is complete.
+    fn reserve(&mut self, bytes: usize, bitrate: u64) -> Instant {
+        let now = Instant::now();
+        let start = self.busy_until.map_or(now, |busy_until| busy_until.max(now));
+        let transfer = Duration::from_secs_f64(bytes as f64 * 8.0 / bitrate as f64);
+
+        let end = start + transfer;
+        self.busy_until = Some(end);
+
+        end
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShapingSettings {
+    /// Maximum bitrate in bits per second.
+    pub max_bitrate: Option<u64>,
+    /// Delay added to every read.
+    pub latency: Duration,
+    /// Maximum random deviation from `latency`.
+    pub jitter: Duration,
+    /// Whether to share the bandwidth with the other instances.
+    pub shared: bool,
+}
+
+impl ShapingSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.max_bitrate.is_some() || !self.latency.is_zero() || !self.jitter.is_zero()
+    }
+}
+
+#[derive(Debug)]
+pub struct Shaper {
+    settings: ShapingSettings,
+    link: Arc<Mutex<Link>>,
+    rng: Mutex<Rng>,
+}
+
+impl Shaper {
+    pub fn new(settings: ShapingSettings, seed: u64) -> Self {
+        let link = match settings.shared {
+            true => SHARED_LINK.clone(),
+            false => Arc::new(Mutex::new(Link::default())),
+        };
+
+        Shaper {
+            settings,
+            link,
+            rng: Mutex::new(Rng::new(seed)),
+        }
+    }
+
+    /// Latency of the next request, uniformly distributed in
+    /// `latency ± jitter`.
+    fn request_latency(&self) -> Duration {
+        let jitter = self.settings.jitter.as_secs_f64() * (2.0 * self.rng.lock().unwrap().next_f64() - 1.0);
+        let latency = self.settings.latency.as_secs_f64() + jitter;
+
+        Duration::from_secs_f64(latency.max(0.0))
+    }
+
+    /// Reads through `read`, delaying the request by the latency and the
+    /// response until the data would have been transferred over the link.
+    /// Concurrent reads share the link.
+    pub fn read(
+        &self,
+        offset: u64,
+        size: u32,
+        read: impl FnOnce(u64, u32) -> Result<gst::Buffer, gst::FlowError>,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        let latency = self.request_latency();
+        if !latency.is_zero() {
+            std::thread::sleep(latency);
+        }
+
+        let buffer = read(offset, size)?;
+
+        if let Some(bitrate) = self.settings.max_bitrate {
+            let done = self.link.lock().unwrap().reserve(buffer.size(), bitrate);
+            if let Some(remaining) = done.checked_duration_since(Instant::now()) {
+                std::thread::sleep(remaining);
+            }
+        }
+
+        Ok(buffer)
+    }
+}
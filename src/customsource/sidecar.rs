@@ -0,0 +1,873 @@
+//! Metadata from a sidecar file delivered next to the media, e.g. camera
+//! metadata, reel names or timecode, pushed downstream as tags and a TOC.
+//!
+//! For `clip.mov` the sidecar is the first of `clip.json`, `clip.xml`,
+//! `clip.mov.json` and `clip.mov.xml` that exists. Both formats are parsed
+//! to the same tree, in which fields are selected with dot-separated paths:
+//!
+//! ```text
+//! {"reel": "A001", "camera": {"model": "X"}}      reel, camera.model
+//! <clip reel="A001"><camera model="X"/></clip>   reel, camera.model
+//! ```
+//!
+//! Paths of XML documents start below the root element, attributes and
+//! child elements being both fields of their element. Arrays, and elements
+//! repeated in XML, match all their items.
+//!
+//! Fields are given as `path[=tag]` separated by `,`, the value of `path`
+//! being added to `tag` or, without tag, to `extended-comment` as
+//! `path=value`. Markers are the items at a path, each with a `start` and
+//! optional `end` and `title` fields, the times being seconds or
+//! `[HH:]MM:SS[.fff]`.
+
+use gst::glib::prelude::StaticType;
+use gst::glib::value::ToSendValue;
+
+use std::path::{Path, PathBuf};
+
+const EXTENSIONS: &[&str] = &["json", "xml"];
+
+/// Deepest nesting of objects, arrays or elements accepted, the parsers
+/// being recursive.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Value(String),
+    Map(Vec<(String, Node)>),
+    List(Vec<Node>),
+}
+
+impl Node {
+    /// Collects the nodes at `path`, the items of lists being matched each.
+    fn find_all<'a>(&'a self, path: &[&str], found: &mut Vec<&'a Node>) {
+        match (self, path) {
+            (Node::List(items), _) => items.iter().for_each(|item| item.find_all(path, found)),
+            (node, []) => found.push(node),
+            (Node::Map(fields), [name, rest @ ..]) => fields
+                .iter()
+                .filter(|(key, _)| key == name)
+                .for_each(|(_, node)| node.find_all(rest, found)),
+            (Node::Value(_), _) => (),
+        }
+    }
+
+    fn find(&self, path: &str) -> Vec<&Node> {
+        let path = path.split('.').filter(|name| !name.is_empty()).collect::<Vec<_>>();
+
+        let mut found = Vec::new();
+        self.find_all(&path, &mut found);
+        found
+    }
+
+    /// The first value at `path`.
+    fn value(&self, path: &str) -> Option<&str> {
+        self.find(path).into_iter().find_map(|node| match node {
+            Node::Value(value) => Some(value.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// Parses JSON, `null` values being dropped.
+struct JsonParser<'a> {
+    input: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse(input: &'a str) -> Result<Node, String> {
+        let mut parser = JsonParser { input, pos: 0, depth: 0 };
+
+        parser.skip_whitespace();
+        let node = parser.value()?.unwrap_or(Node::Map(Vec::new()));
+        parser.skip_whitespace();
+        if parser.pos < input.len() {
+            return Err(parser.error("trailing data"));
+        }
+
+        Ok(node)
+    }
+
+    fn error(&self, reason: &str) -> String {
+        format!("Invalid JSON at byte {}: {reason}", self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Option<Node>, String> {
+        self.skip_whitespace();
+
+        let node = match self.peek() {
+            Some(b'{') => self.nested(Self::object)?,
+            Some(b'[') => self.nested(Self::array)?,
+            Some(b'"') => Node::Value(self.string()?),
+            Some(b'-' | b'0'..=b'9') => Node::Value(self.number()?),
+            Some(_) => {
+                for (literal, node) in [("true", Some("true")), ("false", Some("false")), ("null", None)] {
+                    if self.input[self.pos..].starts_with(literal) {
+                        self.pos += literal.len();
+                        return Ok(node.map(|value| Node::Value(String::from(value))));
+                    }
+                }
+
+                return Err(self.error("unexpected character"));
+            }
+            None => return Err(self.error("unexpected end")),
+        };
+
+        Ok(Some(node))
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Node, String>) -> Result<Node, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+
+        self.depth += 1;
+        let node = parse(self);
+        self.depth -= 1;
+
+        node
+    }
+
+    fn object(&mut self) -> Result<Node, String> {
+        self.expect(b'{')?;
+
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Node::Map(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(b':')?;
+            if let Some(node) = self.value()? {
+                fields.push((key, node));
+            }
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Node::Map(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Node, String> {
+        self.expect(b'[')?;
+
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Node::List(items));
+        }
+
+        loop {
+            if let Some(node) = self.value()? {
+                items.push(node);
+            }
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Node::List(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected string"));
+        }
+        self.pos += 1;
+
+        let mut value = String::new();
+        loop {
+            let Some(c) = self.input[self.pos..].chars().next() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += c.len_utf8();
+
+            match c {
+                '"' => return Ok(value),
+                '\\' => {
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let mut code = self.hex4()?;
+                            // Characters out of the BMP are escaped as
+                            // surrogate pairs, unpaired surrogates are
+                            // replaced and the escape after them kept
+                            if (0xd800..0xdc00).contains(&code) {
+                                let low = self.input[self.pos..]
+                                    .strip_prefix("\\u")
+                                    .and_then(|rest| rest.get(..4))
+                                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                    .filter(|low| (0xdc00..0xe000).contains(low));
+
+                                if let Some(low) = low {
+                                    self.pos += 6;
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                }
+                            }
+                            value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.pos += 1;
+                    value.push(escaped);
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let code = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+            self.pos += 1;
+        }
+
+        let number = &self.input[start..self.pos];
+        match number.parse::<f64>() {
+            Ok(_) => Ok(String::from(number)),
+            Err(_) => Err(self.error("invalid number")),
+        }
+    }
+}
+
+/// Parses XML to the element tree below the root element. The prolog,
+/// comments and processing instructions are skipped, and only the
+/// predefined and numeric entities are known.
+struct XmlParser<'a> {
+    input: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> XmlParser<'a> {
+    fn parse(input: &'a str) -> Result<Node, String> {
+        let mut parser = XmlParser { input, pos: 0, depth: 0 };
+
+        parser.skip_misc()?;
+        if !parser.rest().starts_with('<') {
+            return Err(parser.error("expected root element"));
+        }
+        parser.pos += 1;
+        let (_, root) = parser.element()?;
+
+        parser.skip_misc()?;
+        if parser.pos < input.len() {
+            return Err(parser.error("trailing data"));
+        }
+
+        Ok(root)
+    }
+
+    fn error(&self, reason: &str) -> String {
+        format!("Invalid XML at byte {}: {reason}", self.pos)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), String> {
+        match self.rest().find(end) {
+            Some(index) => {
+                self.pos += index + end.len();
+                Ok(())
+            }
+            None => Err(self.error(&format!("missing '{end}'"))),
+        }
+    }
+
+    /// Skips whitespace, comments, processing instructions and doctypes.
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!DOCTYPE") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected name"));
+        }
+        self.pos += len;
+
+        Ok(String::from(&rest[..len]))
+    }
+
+    /// Parses an element, after its `<`.
+    fn element(&mut self) -> Result<(String, Node), String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+
+        self.depth += 1;
+        let element = self.element_content();
+        self.depth -= 1;
+
+        element
+    }
+
+    fn element_content(&mut self) -> Result<(String, Node), String> {
+        let name = self.name()?;
+        let mut fields = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok((name, Node::Map(fields)));
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(self.error("expected '='"));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+
+            let Some(quote) = self.rest().chars().next().filter(|c| matches!(c, '"' | '\'')) else {
+                return Err(self.error("expected quoted attribute value"));
+            };
+            self.pos += 1;
+            let end = self.rest().find(quote).ok_or_else(|| self.error("unterminated attribute value"))?;
+            let value = self.unescape(&self.rest()[..end])?;
+            self.pos += end + 1;
+
+            fields.push((attribute, Node::Value(value)));
+        }
+
+        let mut text = String::new();
+        loop {
+            let rest = self.rest();
+
+            if let Some(rest) = rest.strip_prefix("</") {
+                let len = rest.find('>').ok_or_else(|| self.error("unterminated end tag"))?;
+                if rest[..len].trim_end() != name {
+                    return Err(self.error(&format!("expected end of element {name:?}")));
+                }
+                self.pos += 2 + len + 1;
+                break;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if let Some(rest) = rest.strip_prefix("<![CDATA[") {
+                let len = rest.find("]]>").ok_or_else(|| self.error("unterminated CDATA section"))?;
+                text.push_str(&rest[..len]);
+                self.pos += "<![CDATA[".len() + len + "]]>".len();
+            } else if rest.starts_with('<') {
+                self.pos += 1;
+                fields.push(self.element()?);
+            } else if rest.is_empty() {
+                return Err(self.error(&format!("unterminated element {name:?}")));
+            } else {
+                let len = rest.find('<').unwrap_or(rest.len());
+                text.push_str(&self.unescape(&rest[..len])?);
+                self.pos += len;
+            }
+        }
+
+        // Elements with only text are values, the text of elements with
+        // attributes or children is their `#text` field
+        let text = text.trim();
+        let node = match fields.is_empty() {
+            true => Node::Value(String::from(text)),
+            false => {
+                if !text.is_empty() {
+                    fields.push((String::from("#text"), Node::Value(String::from(text))));
+                }
+                Node::Map(fields)
+            }
+        };
+
+        Ok((name, node))
+    }
+
+    fn unescape(&self, text: &str) -> Result<String, String> {
+        let mut unescaped = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find('&') {
+            unescaped.push_str(&rest[..start]);
+            let end = rest[start..].find(';').ok_or_else(|| self.error("unterminated entity"))? + start;
+
+            let entity = &rest[start + 1..end];
+            let c = match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()).and_then(char::from_u32),
+                },
+            };
+            unescaped.push(c.ok_or_else(|| self.error(&format!("unknown entity '&{entity};'")))?);
+
+            rest = &rest[end + 1..];
+        }
+        unescaped.push_str(rest);
+
+        Ok(unescaped)
+    }
+}
+
+/// A field of the sidecar pushed as a tag.
+#[derive(Debug, Clone)]
+pub struct Field {
+    path: String,
+    /// `None` for `extended-comment`.
+    tag: Option<String>,
+}
+
+/// Parses the `path[=tag]` fields of `sidecar-fields`.
+pub fn parse_fields(fields: &str) -> Result<Vec<Field>, String> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (path, tag) = match field.split_once('=') {
+                Some((path, tag)) => (path.trim(), Some(tag.trim())),
+                None => (field, None),
+            };
+
+            if let Some(tag) = tag {
+                if !gst::tags::tag_exists(tag) {
+                    return Err(format!("Unknown tag {tag:?} for sidecar field {path:?}"));
+                }
+                let tag_type = gst::tags::tag_get_type(tag);
+                if !SUPPORTED_TYPES.iter().any(|supported| supported() == tag_type) {
+                    return Err(format!("Tag {tag:?} of type {tag_type} is not supported for sidecar fields"));
+                }
+            }
+
+            Ok(Field {
+                path: String::from(path),
+                tag: tag.map(String::from),
+            })
+        })
+        .collect()
+}
+
+/// Types of the tags the fields can be converted to.
+const SUPPORTED_TYPES: &[fn() -> gst::glib::Type] = &[
+    String::static_type,
+    u32::static_type,
+    i32::static_type,
+    u64::static_type,
+    i64::static_type,
+    f64::static_type,
+    gst::DateTime::static_type,
+];
+
+/// Converts the text of a field to the type of `tag`, `None` if it doesn't
+/// parse or the type is not supported.
+fn tag_value(tag: &str, value: &str) -> Option<gst::glib::SendValue> {
+    let tag_type = gst::tags::tag_get_type(tag);
+
+    if tag_type == String::static_type() {
+        Some(value.to_send_value())
+    } else if tag_type == u32::static_type() {
+        value.parse::<u32>().ok().map(|value| value.to_send_value())
+    } else if tag_type == i32::static_type() {
+        value.parse::<i32>().ok().map(|value| value.to_send_value())
+    } else if tag_type == u64::static_type() {
+        value.parse::<u64>().ok().map(|value| value.to_send_value())
+    } else if tag_type == i64::static_type() {
+        value.parse::<i64>().ok().map(|value| value.to_send_value())
+    } else if tag_type == f64::static_type() {
+        value.parse::<f64>().ok().map(|value| value.to_send_value())
+    } else if tag_type == gst::DateTime::static_type() {
+        gst::DateTime::from_iso8601_string(value).ok().map(|value| value.to_send_value())
+    } else {
+        None
+    }
+}
+
+/// Parses seconds or `[HH:]MM:SS[.fff]`.
+fn parse_time(time: &str) -> Option<gst::ClockTime> {
+    let seconds = time.trim().split(':').try_fold(0.0, |seconds, part| {
+        part.parse::<f64>().ok().filter(|part| *part >= 0.0).map(|part| seconds * 60.0 + part)
+    })?;
+
+    Some(gst::ClockTime::from_nseconds((seconds * 1_000_000_000.0).round() as u64))
+}
+
+#[derive(Debug)]
+pub struct Sidecar {
+    path: PathBuf,
+    root: Node,
+}
+
+impl Sidecar {
+    /// The sidecar of the media at `location`, if there is one.
+    pub fn find(location: &Path) -> Option<PathBuf> {
+        let with_extension = |extension: &str| {
+            let mut path = location.as_os_str().to_os_string();
+            path.push(".");
+            path.push(extension);
+            PathBuf::from(path)
+        };
+
+        let candidates = EXTENSIONS
+            .iter()
+            .map(|extension| location.with_extension(extension))
+            .chain(EXTENSIONS.iter().map(|extension| with_extension(extension)));
+
+        candidates.into_iter().find(|path| path != location && path.is_file())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|err| format!("Could not read {path:?}: {err}"))?;
+
+        let root = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => JsonParser::parse(&content)?,
+            Some("xml") => XmlParser::parse(&content)?,
+            _ => return Err(format!("Unknown sidecar format of {path:?}")),
+        };
+
+        Ok(Sidecar {
+            path: path.to_path_buf(),
+            root,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Tags from `fields`, or from all the values at the top level without
+    /// fields.
+    pub fn tags(&self, fields: Option<&[Field]>) -> gst::TagList {
+        let mut tags = gst::TagList::new();
+
+        {
+            let tags = tags.get_mut().unwrap();
+            tags.set_scope(gst::TagScope::Global);
+
+            let add_comment = |tags: &mut gst::TagListRef, path: &str, value: &str| {
+                tags.add::<gst::tags::ExtendedComment>(&format!("{path}={value}").as_str(), gst::TagMergeMode::Append);
+            };
+
+            match fields {
+                Some(fields) => {
+                    for field in fields {
+                        let Some(value) = self.root.value(&field.path) else {
+                            continue;
+                        };
+
+                        match field.tag.as_deref().and_then(|tag| Some((tag, tag_value(tag, value)?))) {
+                            Some((tag, value)) => {
+                                let _ = tags.add_value(tag, &value, gst::TagMergeMode::Append);
+                            }
+                            None => add_comment(tags, &field.path, value),
+                        }
+                    }
+                }
+                None => {
+                    if let Node::Map(fields) = &self.root {
+                        for (name, node) in fields {
+                            if let Node::Value(value) = node {
+                                add_comment(tags, name, value);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        tags
+    }
+
+    /// A TOC with a chapter for each of the markers at `path`.
+    pub fn toc(&self, path: &str) -> Option<gst::Toc> {
+        let markers = self
+            .root
+            .find(path)
+            .into_iter()
+            .filter_map(|marker| {
+                let start = parse_time(marker.value("start")?)?;
+                let end = marker.value("end").and_then(parse_time);
+                let title = marker.value("title");
+
+                Some((start, end, title))
+            })
+            .collect::<Vec<_>>();
+        if markers.is_empty() {
+            return None;
+        }
+
+        let mut edition = gst::TocEntry::new(gst::TocEntryType::Edition, "sidecar");
+        for (index, (start, end, title)) in markers.iter().enumerate() {
+            // Markers without end last until the next one
+            let stop = end.or_else(|| markers.get(index + 1).map(|next| next.0));
+
+            let mut chapter = gst::TocEntry::new(gst::TocEntryType::Chapter, &format!("sidecar-marker{index}"));
+            {
+                let chapter = chapter.get_mut().unwrap();
+                chapter.set_start_stop_times(
+                    start.nseconds() as i64,
+                    stop.map_or(-1, |stop| stop.nseconds() as i64),
+                );
+
+                if let Some(title) = title {
+                    let mut tags = gst::TagList::new();
+                    tags.get_mut().unwrap().add::<gst::tags::Title>(title, gst::TagMergeMode::Replace);
+                    chapter.set_tags(tags);
+                }
+            }
+            edition.get_mut().unwrap().append_sub_entry(chapter);
+        }
+
+        let mut toc = gst::Toc::new(gst::TocScope::Global);
+        toc.get_mut().unwrap().append_entry(edition);
+
+        Some(toc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(value: &str) -> Node {
+        Node::Value(String::from(value))
+    }
+
+    fn json_string(json: &str) -> String {
+        match JsonParser::parse(&format!("{{\"s\": \"{json}\"}}")).unwrap() {
+            Node::Map(fields) => match &fields[0].1 {
+                Node::Value(value) => value.clone(),
+                node => panic!("not a value: {node:?}"),
+            },
+            node => panic!("not an object: {node:?}"),
+        }
+    }
+
+    #[test]
+    fn json() {
+        let root = JsonParser::parse(r#"{"reel": "A001", "take": 3, "ok": true, "none": null,
+            "camera": {"model": "X", "lenses": [{"mm": 35}, {"mm": 50}]}}"#)
+        .unwrap();
+
+        assert_eq!(root.value("reel"), Some("A001"));
+        assert_eq!(root.value("take"), Some("3"));
+        assert_eq!(root.value("ok"), Some("true"));
+        assert_eq!(root.value("none"), None);
+        assert_eq!(root.value("camera.model"), Some("X"));
+        assert_eq!(root.find("camera.lenses.mm"), [&value("35"), &value("50")]);
+
+        assert_eq!(JsonParser::parse(" null ").unwrap(), Node::Map(Vec::new()));
+        for invalid in ["", r#"{"a": 1} x"#, r#"{"a": "b"#, r#"{"a" 1}"#, "[1,]", r#"{"a": 1.2.3}"#, "nope"] {
+            assert!(JsonParser::parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(json_string(r#"a\"b\\c\/d\n\t"#), "a\"b\\c/d\n\t");
+        assert_eq!(json_string(r"\u00e9\u20AC"), "é€");
+        assert_eq!(json_string(r"\ud83c\udfac"), "🎬");
+
+        // Unpaired surrogates are replaced without swallowing what follows
+        assert_eq!(json_string(r"\ud83cx"), "\u{fffd}x");
+        assert_eq!(json_string(r"\ud83c\u0041"), "\u{fffd}A");
+        assert_eq!(json_string(r"\ud83c\ud83c\udfac"), "\u{fffd}🎬");
+        assert_eq!(json_string(r"\udfacA"), "\u{fffd}A");
+
+        for invalid in [r"\x", r"\u12", r"\uzzzz"] {
+            assert!(JsonParser::parse(&format!("{{\"s\": \"{invalid}\"}}")).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn xml() {
+        let root = XmlParser::parse(
+            r#"<?xml version="1.0"?>
+            <!-- exported -->
+            <clip reel="A001" note='a &amp; b'>
+                <camera model="X">Main &#x263A;</camera>
+                <scene>12<!-- c --></scene>
+                <marker start="1"/>
+                <marker start="2"/>
+                <script><![CDATA[<b>raw</b>]]></script>
+            </clip>"#,
+        )
+        .unwrap();
+
+        assert_eq!(root.value("reel"), Some("A001"));
+        assert_eq!(root.value("note"), Some("a & b"));
+        assert_eq!(root.value("camera.model"), Some("X"));
+        assert_eq!(root.value("camera.#text"), Some("Main ☺"));
+        assert_eq!(root.value("scene"), Some("12"));
+        assert_eq!(root.value("script"), Some("<b>raw</b>"));
+        assert_eq!(root.find("marker").len(), 2);
+
+        for invalid in ["", "<a>", "<a></b>", "<a b=c/>", "<a>&nope;</a>", "<a/><b/>"] {
+            assert!(XmlParser::parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn nesting() {
+        let json = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let xml = |depth| format!("{}{}", "<a>".repeat(depth), "</a>".repeat(depth));
+
+        assert!(JsonParser::parse(&json(MAX_DEPTH)).is_ok());
+        assert!(XmlParser::parse(&xml(MAX_DEPTH)).is_ok());
+
+        // Rejected rather than overflowing the stack
+        assert!(JsonParser::parse(&json(MAX_DEPTH + 1)).is_err());
+        assert!(JsonParser::parse(&json(100_000)).is_err());
+        assert!(XmlParser::parse(&xml(MAX_DEPTH + 1)).is_err());
+        assert!(XmlParser::parse(&xml(100_000)).is_err());
+    }
+
+    #[test]
+    fn fields() {
+        gst::init().unwrap();
+
+        let fields = parse_fields(" reel = title, camera.model ,,").unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!((fields[0].path.as_str(), fields[0].tag.as_deref()), ("reel", Some("title")));
+        assert_eq!((fields[1].path.as_str(), fields[1].tag.as_deref()), ("camera.model", None));
+
+        assert!(parse_fields("reel=no-such-tag").is_err());
+        // Samples can't be made from text
+        assert!(parse_fields("poster=image").is_err());
+
+        let sidecar = Sidecar {
+            path: PathBuf::from("clip.json"),
+            root: JsonParser::parse(r#"{"reel": "A001", "track": "7", "camera": {"model": "X"}}"#).unwrap(),
+        };
+        let tags = sidecar.tags(Some(&parse_fields("reel=title,track=track-number,camera.model").unwrap()));
+        assert_eq!(tags.get::<gst::tags::Title>().unwrap().get(), "A001");
+        assert_eq!(tags.get::<gst::tags::TrackNumber>().unwrap().get(), 7);
+        assert_eq!(tags.get::<gst::tags::ExtendedComment>().unwrap().get(), "camera.model=X");
+    }
+
+    #[test]
+    fn times() {
+        let time = |time| parse_time(time).map(gst::ClockTime::mseconds);
+
+        assert_eq!(time("90"), Some(90_000));
+        assert_eq!(time(" 1.5 "), Some(1_500));
+        assert_eq!(time("01:30"), Some(90_000));
+        assert_eq!(time("1:00:00.25"), Some(3_600_250));
+        assert_eq!(time("-1"), None);
+        assert_eq!(time("1:-30"), None);
+        assert_eq!(time("a"), None);
+        assert_eq!(time(""), None);
+    }
+
+    #[test]
+    fn toc() {
+        gst::init().unwrap();
+
+        let sidecar = Sidecar {
+            path: PathBuf::from("clip.json"),
+            root: JsonParser::parse(
+                r#"{"markers": [{"start": "0:10", "title": "Intro"}, {"start": "20", "end": "25"}, {"end": "30"}]}"#,
+            )
+            .unwrap(),
+        };
+
+        assert!(sidecar.toc("nothing").is_none());
+
+        let toc = sidecar.toc("markers").unwrap();
+        let edition = &toc.entries()[0];
+        let chapters = edition.sub_entries();
+        // The marker without start is skipped
+        assert_eq!(chapters.len(), 2);
+
+        // Without end, a marker lasts until the next one
+        assert_eq!(chapters[0].start_stop_times(), Some((10_000_000_000, 20_000_000_000)));
+        assert_eq!(chapters[0].tags().unwrap().get::<gst::tags::Title>().unwrap().get(), "Intro");
+        assert_eq!(chapters[1].start_stop_times(), Some((20_000_000_000, 25_000_000_000)));
+        assert!(chapters[1].tags().is_none());
+    }
+}
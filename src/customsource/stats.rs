@@ -0,0 +1,129 @@
+use gst::prelude::*;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the read size histogram, the last bucket
+/// counts everything bigger.
+const SIZE_BUCKETS: [u64; 6] = [1 << 10, 4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20];
+
+/// Number of backend latencies kept to compute the percentiles.
+const LATENCY_SAMPLES: usize = 1024;
+
+/// Statistics about the reads done through `CustomSource`.
+#[derive(Debug)]
+pub struct Stats {
+    bytes_read: u64,
+    range_calls: u64,
+    seeks: u64,
+    next_offset: Option<u64>,
+    size_histogram: [u64; SIZE_BUCKETS.len() + 1],
+    latencies: VecDeque<Duration>,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            bytes_read: 0,
+            range_calls: 0,
+            seeks: 0,
+            next_offset: None,
+            size_histogram: [0; SIZE_BUCKETS.len() + 1],
+            latencies: VecDeque::with_capacity(LATENCY_SAMPLES),
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+}
+
+impl Stats {
+    /// Records a `range()` call, a read not starting where the previous
+    /// one ended counts as a seek.
+    pub fn record_range(&mut self, offset: u64, size: u32) {
+        self.range_calls += 1;
+
+        if self.next_offset.is_some_and(|next_offset| next_offset != offset) {
+            self.seeks += 1;
+        }
+
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| size as u64 <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.size_histogram[bucket] += 1;
+    }
+
+    /// Records the outcome of a `range()` call, wherever the data came
+    /// from.
+    pub fn record_read(&mut self, offset: u64, read: usize) {
+        self.bytes_read += read as u64;
+        self.next_offset = Some(offset + read as u64);
+    }
+
+    /// Records the time a read from the backend took, the reads served from
+    /// the mirror, the cache or the prefetched data don't count.
+    pub fn record_backend_latency(&mut self, latency: Duration) {
+        if self.latencies.len() == LATENCY_SAMPLES {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+    }
+
+    /// Records whether a block was found in the shared cache.
+    pub fn record_cache_lookup(&mut self, hit: bool) {
+        match hit {
+            true => self.cache_hits += 1,
+            false => self.cache_misses += 1,
+        }
+    }
+
+    fn latency_percentiles(&self, percentiles: &[u64]) -> Vec<u64> {
+        let mut latencies = self.latencies.iter().copied().collect::<Vec<_>>();
+        latencies.sort_unstable();
+
+        percentiles
+            .iter()
+            .map(|percentile| {
+                if latencies.is_empty() {
+                    return 0;
+                }
+
+                let index = (latencies.len() - 1) * *percentile as usize / 100;
+                latencies[index].as_nanos() as u64
+            })
+            .collect()
+    }
+
+    pub fn to_structure(&self) -> gst::Structure {
+        let lookups = self.cache_hits + self.cache_misses;
+        let cache_hit_ratio = if lookups == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / lookups as f64
+        };
+
+        let latencies = self.latency_percentiles(&[50, 90, 99]);
+
+        gst::Structure::builder("customsource-stats")
+            .field("bytes-read", self.bytes_read)
+            .field("range-calls", self.range_calls)
+            .field("seeks", self.seeks)
+            .field(
+                "size-histogram-bounds",
+                gst::Array::new(SIZE_BUCKETS.iter().map(|bound| bound.to_send_value())),
+            )
+            .field(
+                "size-histogram",
+                gst::Array::new(self.size_histogram.iter().map(|count| count.to_send_value())),
+            )
+            .field("latency-p50", latencies[0])
+            .field("latency-p90", latencies[1])
+            .field("latency-p99", latencies[2])
+            .field("cache-hits", self.cache_hits)
+            .field("cache-misses", self.cache_misses)
+            .field("cache-hit-ratio", cache_hit_ratio)
+            .build()
+    }
+}
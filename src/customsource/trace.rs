@@ -0,0 +1,130 @@
+//! Recording of the `range()` calls done on `CustomSource`.
+//!
+//! A trace is a JSON lines file with one object per call, e.g.
+//!
+//! ```json
+//! {"timestamp":1500000,"offset":0,"size":4096,"read":4096,"latency":35000}
+//! ```
+//!
+//! `timestamp` and `latency` are in nanoseconds, `timestamp` being relative
+//! to the start of the recording. `read` is the number of bytes returned or
+//! -1 if the read failed. Traces can be replayed with `trace-replay`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub timestamp: Duration,
+    pub offset: u64,
+    pub size: u32,
+    pub read: Option<usize>,
+    pub latency: Duration,
+}
+
+impl TraceEntry {
+    pub fn to_json(self) -> String {
+        format!(
+            "{{\"timestamp\":{},\"offset\":{},\"size\":{},\"read\":{},\"latency\":{}}}",
+            self.timestamp.as_nanos(),
+            self.offset,
+            self.size,
+            self.read.map_or(-1, |read| read as i64),
+            self.latency.as_nanos(),
+        )
+    }
+
+    /// Parses a line as written by `to_json()`, unknown fields are ignored.
+    pub fn from_json(line: &str) -> Option<Self> {
+        let fields = line.trim().strip_prefix('{')?.strip_suffix('}')?;
+
+        let mut timestamp = None;
+        let mut offset = None;
+        let mut size = None;
+        let mut read = None;
+        let mut latency = None;
+
+        for field in fields.split(',') {
+            let (key, value) = field.split_once(':')?;
+            let value = value.trim();
+
+            match key.trim().trim_matches('"') {
+                "timestamp" => timestamp = Some(Duration::from_nanos(value.parse().ok()?)),
+                "offset" => offset = Some(value.parse().ok()?),
+                "size" => size = Some(value.parse().ok()?),
+                "read" => read = Some(usize::try_from(value.parse::<i64>().ok()?).ok()),
+                "latency" => latency = Some(Duration::from_nanos(value.parse().ok()?)),
+                _ => (),
+            }
+        }
+
+        Some(TraceEntry {
+            timestamp: timestamp?,
+            offset: offset?,
+            size: size?,
+            read: read.unwrap_or(None),
+            latency: latency.unwrap_or_default(),
+        })
+    }
+}
+
+pub struct TraceWriter {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl TraceWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(TraceWriter {
+            writer: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        started: Instant,
+        offset: u64,
+        size: u32,
+        read: Option<usize>,
+    ) -> io::Result<()> {
+        let entry = TraceEntry {
+            timestamp: started.saturating_duration_since(self.start),
+            offset,
+            size,
+            read,
+            latency: started.elapsed(),
+        };
+
+        writeln!(self.writer, "{}", entry.to_json())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads all entries of a trace file, failing on the first malformed line.
+pub fn read_trace(path: &Path) -> io::Result<Vec<TraceEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = TraceEntry::from_json(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid trace entry on line {}: {line:?}", index + 1),
+            )
+        })?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
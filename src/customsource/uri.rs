@@ -0,0 +1,180 @@
+use gst::glib;
+use url::{Host, Url};
+
+use std::path::{Path, PathBuf};
+
+/// Resolves a relative `location` against `base_directory`, or against the
+/// current directory if no base is given or the base is relative itself.
+pub fn absolute_path(location: &Path, base_directory: Option<&Path>) -> Option<PathBuf> {
+    if location.is_absolute() {
+        return Some(location.to_path_buf());
+    }
+
+    let base = match base_directory {
+        Some(base) if base.is_absolute() => base.to_path_buf(),
+        Some(base) => std::env::current_dir().ok()?.join(base),
+        None => std::env::current_dir().ok()?,
+    };
+
+    Some(base.join(location))
+}
+
+/// Builds the `file://` URI of `location`, percent-encoding anything that is
+/// not allowed in an URI, including bytes of non-UTF-8 file names.
+pub fn path_to_uri(location: &Path, base_directory: Option<&Path>) -> Option<Url> {
+    let path = absolute_path(location, base_directory)?;
+
+    Url::from_file_path(path).ok()
+}
+
+/// Builds the URI identifying the file at `location` whatever the path used
+/// to reach it, resolving symbolic links and `..` components when it exists.
+pub fn canonical_uri(location: &Path) -> Option<Url> {
+    let path = std::fs::canonicalize(location).unwrap_or_else(|_| location.to_path_buf());
+
+    Url::from_file_path(path).ok()
+}
+
+fn is_local_host(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(domain) => {
+            domain.eq_ignore_ascii_case("localhost")
+                || domain.eq_ignore_ascii_case(glib::host_name().as_str())
+        }
+        Host::Ipv4(addr) => addr.is_loopback(),
+        Host::Ipv6(addr) => addr.is_loopback(),
+    }
+}
+
+/// Parses a `file://` URI into a local path.
+///
+/// `localhost`, loopback addresses and the name of this machine are accepted
+/// as host, other hosts are rejected as they can't be accessed as local files.
+pub fn uri_to_path(uri: &str) -> Result<PathBuf, glib::Error> {
+    let mut url = Url::parse(uri).map_err(|err| {
+        glib::Error::new(gst::URIError::BadUri, &format!("Invalid URI {uri:?}: {err}"))
+    })?;
+
+    if url.scheme() != "file" {
+        return Err(glib::Error::new(
+            gst::URIError::UnsupportedProtocol,
+            &format!("Unsupported protocol {:?}", url.scheme()),
+        ));
+    }
+
+    if let Some(host) = url.host() {
+        if !is_local_host(&host) {
+            return Err(glib::Error::new(
+                gst::URIError::BadReference,
+                &format!("Host {host} of {uri:?} is not the local host"),
+            ));
+        }
+
+        // `Url::to_file_path()` only accepts URIs without host
+        url = Url::parse(&format!("file://{}", url.path())).map_err(|err| {
+            glib::Error::new(gst::URIError::BadUri, &format!("Invalid URI {uri:?}: {err}"))
+        })?;
+    }
+
+    url.to_file_path().map_err(|_| {
+        glib::Error::new(
+            gst::URIError::BadUri,
+            &format!("Could not get a file location from {uri:?}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn round_trip(path: &Path) -> PathBuf {
+        let url = path_to_uri(path, None).unwrap();
+
+        uri_to_path(url.as_str()).unwrap()
+    }
+
+    #[test]
+    fn percent_encoding() {
+        let path = Path::new("/media/A 100%#?é.mov");
+        let url = path_to_uri(path, None).unwrap();
+
+        assert_eq!(url.as_str(), "file:///media/A%20100%25%23%3F%C3%A9.mov");
+        assert_eq!(round_trip(path), path);
+        assert_eq!(uri_to_path("file:///media/A%20b.mov").unwrap(), Path::new("/media/A b.mov"));
+    }
+
+    #[test]
+    fn non_utf8_paths() {
+        let path = Path::new(OsStr::from_bytes(b"/media/clip\xff\xfe.mov"));
+        let url = path_to_uri(path, None).unwrap();
+
+        assert_eq!(url.as_str(), "file:///media/clip%FF%FE.mov");
+        assert_eq!(round_trip(path), path);
+    }
+
+    #[test]
+    fn local_hosts() {
+        for uri in ["file://localhost/media/a.mov", "file://LOCALHOST/media/a.mov", "file://127.0.0.1/media/a.mov"] {
+            assert_eq!(uri_to_path(uri).unwrap(), Path::new("/media/a.mov"), "{uri}");
+        }
+
+        let uri = format!("file://{}/media/a.mov", glib::host_name());
+        assert_eq!(uri_to_path(&uri).unwrap(), Path::new("/media/a.mov"));
+
+        let err = uri_to_path("file://example.com/media/a.mov").unwrap_err();
+        assert_eq!(err.kind::<gst::URIError>(), Some(gst::URIError::BadReference));
+    }
+
+    #[test]
+    fn base_directory() {
+        let cwd = std::env::current_dir().unwrap();
+
+        assert_eq!(absolute_path(Path::new("/a.mov"), Some(Path::new("/media"))).unwrap(), Path::new("/a.mov"));
+        assert_eq!(absolute_path(Path::new("a.mov"), Some(Path::new("/media"))).unwrap(), Path::new("/media/a.mov"));
+        assert_eq!(absolute_path(Path::new("a.mov"), Some(Path::new("media"))).unwrap(), cwd.join("media/a.mov"));
+        assert_eq!(absolute_path(Path::new("a.mov"), None).unwrap(), cwd.join("a.mov"));
+
+        let url = path_to_uri(Path::new("day 1/a.mov"), Some(Path::new("/media"))).unwrap();
+        assert_eq!(url.as_str(), "file:///media/day%201/a.mov");
+        assert_eq!(uri_to_path(url.as_str()).unwrap(), Path::new("/media/day 1/a.mov"));
+    }
+
+    #[test]
+    fn generated_round_trips() {
+        let alphabet: Vec<&[u8]> = vec![
+            b" ", b"%", b"#", b"?", b"&", b";", b"=", b"+", b"@", b":", b"'", b"\"", b"\\", b"\x01", b"\x7f", b"\x80",
+            b"\xc3\xa9", b"\xff", b"a", b"Z", b"0", b"9", b"-", b"_", b".",
+        ];
+
+        // xorshift, so that a failure can be reproduced
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+
+        for _ in 0..1000 {
+            let mut path = Vec::new();
+            for _ in 0..1 + next(4) {
+                path.push(b'/');
+                for _ in 0..1 + next(8) {
+                    path.extend_from_slice(alphabet[next(alphabet.len())]);
+                }
+                // "." and ".." are normalized away by URLs
+                if path.ends_with(b"/.") || path.ends_with(b"/..") {
+                    path.push(b'a');
+                }
+            }
+            path.extend_from_slice(b".mov");
+
+            let path = Path::new(OsStr::from_bytes(&path));
+            assert_eq!(round_trip(path), path, "{path:?}");
+        }
+    }
+}
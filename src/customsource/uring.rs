@@ -0,0 +1,413 @@
+//! Local file backend reading through io_uring.
+//!
+//! The file is read in chunks of `CHUNK_SIZE` bytes and the chunks following
+//! the one being read are submitted too, so that up to `depth` reads are in
+//! flight and the kernel keeps the disks busy while the previous chunk is
+//! being processed. With `direct` the file is opened with `O_DIRECT` so the
+//! reads bypass the page cache, which needs buffers and offsets aligned to
+//! the block size of the device: `ALIGNMENT` covers the usual ones.
+//!
+//! Where io_uring is not available, e.g. kernels older than 5.6 or when it
+//! is blocked by a seccomp filter, the file is read with `pread()`.
+
+use super::backend;
+
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "linux")]
+use io_uring::{opcode, types, IoUring};
+
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+const ALIGNMENT: usize = 4096;
+
+/// The ring submitting the reads, one at a time.
+#[cfg(target_os = "linux")]
+struct Ring(IoUring);
+
+#[cfg(target_os = "linux")]
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        IoUring::new(entries).map(Ring)
+    }
+
+    fn entries(&self) -> usize {
+        self.0.params().sq_entries() as usize
+    }
+
+    /// Submits a read of `len` bytes at `offset` of `fd` into `buf`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid until the completion of the read is returned
+    /// by `wait()`.
+    unsafe fn submit_read(&mut self, fd: libc::c_int, buf: *mut u8, len: u32, offset: u64, user_data: u64) -> io::Result<()> {
+        let entry = opcode::Read::new(types::Fd(fd), buf, len).offset(offset).build().user_data(user_data);
+
+        // SAFETY: the buffer outlives the read as guaranteed by the caller
+        unsafe { self.0.submission().push(&entry) }
+            .map_err(|_| io::Error::new(io::ErrorKind::WouldBlock, "Submission queue full"))?;
+
+        loop {
+            match self.0.submit() {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                res => return res.map(|_| ()),
+            }
+        }
+    }
+
+    /// Waits for the next completion, returns its user data and result.
+    fn wait(&mut self) -> io::Result<(u64, i32)> {
+        loop {
+            if let Some(cqe) = self.0.completion().next() {
+                return Ok((cqe.user_data(), cqe.result()));
+            }
+
+            match self.0.submit_and_wait(1) {
+                Err(err) if err.kind() != io::ErrorKind::Interrupted => return Err(err),
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+enum Ring {}
+
+#[cfg(not(target_os = "linux"))]
+impl Ring {
+    fn new(_entries: u32) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "io_uring is only available on Linux"))
+    }
+
+    fn entries(&self) -> usize {
+        match *self {}
+    }
+
+    unsafe fn submit_read(&mut self, _fd: libc::c_int, _buf: *mut u8, _len: u32, _offset: u64, _user_data: u64) -> io::Result<()> {
+        match *self {}
+    }
+
+    fn wait(&mut self) -> io::Result<(u64, i32)> {
+        match *self {}
+    }
+}
+
+/// A buffer aligned for `O_DIRECT`, written by the kernel.
+struct AlignedBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// Only read once the kernel is done writing it
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, ALIGNMENT).unwrap();
+        // SAFETY: non-zero size, zeroed so it never exposes uninitialized memory
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        AlignedBuffer { ptr, len }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: allocated with the same layout in `new()`
+        unsafe { alloc::dealloc(self.ptr, Layout::from_size_align(self.len, ALIGNMENT).unwrap()) };
+    }
+}
+
+/// Part of a chunk, to wrap it in a `gst::Memory` without copying.
+struct ChunkSlice {
+    buffer: Arc<AlignedBuffer>,
+    start: usize,
+    end: usize,
+}
+
+impl AsRef<[u8]> for ChunkSlice {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: in bounds of a buffer the kernel is done with
+        unsafe { std::slice::from_raw_parts(self.buffer.ptr.add(self.start), self.end - self.start) }
+    }
+}
+
+#[derive(Debug)]
+enum ChunkState {
+    InFlight,
+    Done,
+    Failed(io::Error),
+}
+
+struct Chunk {
+    /// User data of the read, chunks of the same index may be in flight
+    /// after seeking back and forth.
+    id: u64,
+    index: u64,
+    buffer: Arc<AlignedBuffer>,
+    filled: usize,
+    state: ChunkState,
+    /// Whether the chunk is still expected to be read, the others are
+    /// dropped once they completed.
+    wanted: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    ring: Option<Ring>,
+    chunks: Vec<Chunk>,
+    next_id: u64,
+}
+
+pub struct UringFile {
+    file: Arc<File>,
+    /// Separate handle for `O_DIRECT`, which `pread()` can't use with
+    /// unaligned buffers.
+    direct_file: Option<File>,
+    size: u64,
+    depth: usize,
+    inner: Mutex<Inner>,
+}
+
+impl UringFile {
+    /// Reads the open `file` at `path` with up to `depth` reads in flight,
+    /// with `O_DIRECT` if `direct`.
+    pub fn open(file: Arc<File>, path: &Path, direct: bool, depth: u32) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        let direct_file = match direct {
+            true => Some(OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(path)?),
+            false => None,
+        };
+
+        let depth = depth.max(1);
+        // Room for the reads of chunks no longer wanted after a seek
+        let ring = Ring::new((depth * 2).next_power_of_two()).ok();
+
+        Ok(UringFile {
+            file,
+            direct_file,
+            size,
+            depth: depth as usize,
+            inner: Mutex::new(Inner {
+                ring,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// The buffered handle on the file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn is_async(&self) -> bool {
+        self.inner.lock().unwrap().ring.is_some()
+    }
+
+    /// Reads `size` bytes at `offset`, `None` at the end of the file.
+    pub fn read(&self, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Reads past the size at opening go through `pread()` in case the
+        // file grew
+        if inner.ring.is_none() || offset >= self.size || size == 0 {
+            return self.pread(offset, size);
+        }
+
+        let end = (offset + size as u64).min(self.size);
+        let (first, last) = (offset / CHUNK_SIZE, (end - 1) / CHUNK_SIZE);
+        let window = (last - first + 1).max(self.depth as u64);
+        let wanted = first..(first + window).min(self.size.div_ceil(CHUNK_SIZE));
+
+        for chunk in &mut inner.chunks {
+            chunk.wanted = wanted.contains(&chunk.index);
+        }
+        inner.chunks.retain(|chunk| chunk.wanted || matches!(chunk.state, ChunkState::InFlight));
+
+        for index in wanted {
+            if !inner.chunks.iter().any(|chunk| chunk.wanted && chunk.index == index) {
+                self.submit(&mut inner, index)?;
+            }
+        }
+
+        let mut buffer = gst::Buffer::new();
+        for index in first..=last {
+            let chunk = self.wait_chunk(&mut inner, index)?;
+
+            let chunk_offset = index * CHUNK_SIZE;
+            let start = offset.max(chunk_offset) - chunk_offset;
+            let chunk_end = (end - chunk_offset).min(chunk.filled as u64);
+            if start >= chunk_end {
+                // Truncated since it was opened
+                break;
+            }
+
+            let memory = gst::Memory::from_slice(ChunkSlice {
+                buffer: chunk.buffer.clone(),
+                start: start as usize,
+                end: chunk_end as usize,
+            });
+            buffer.get_mut().unwrap().append_memory(memory);
+
+            if chunk_end < CHUNK_SIZE && chunk_offset + chunk_end < end {
+                break;
+            }
+        }
+
+        Ok((buffer.size() > 0).then_some(buffer))
+    }
+
+    fn submit(&self, inner: &mut Inner, index: u64) -> io::Result<()> {
+        // Keep the completions from overflowing their queue
+        let limit = inner.ring.as_ref().map_or(0, Ring::entries);
+        while inner.chunks.iter().filter(|chunk| matches!(chunk.state, ChunkState::InFlight)).count() >= limit {
+            self.reap(inner)?;
+        }
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        let chunk = Chunk {
+            id,
+            index,
+            buffer: Arc::new(AlignedBuffer::new(CHUNK_SIZE as usize)),
+            filled: 0,
+            state: ChunkState::InFlight,
+            wanted: true,
+        };
+        self.submit_chunk(inner, &chunk)?;
+        inner.chunks.push(chunk);
+
+        Ok(())
+    }
+
+    /// Submits the read of what is missing from `chunk`.
+    fn submit_chunk(&self, inner: &mut Inner, chunk: &Chunk) -> io::Result<()> {
+        let fd = self.direct_file.as_ref().unwrap_or(&self.file).as_raw_fd();
+        let ring = Self::ring(inner)?;
+
+        let len = (CHUNK_SIZE as usize - chunk.filled) as u32;
+
+        // SAFETY: in bounds of the buffer, which is kept alive by the chunk
+        // until the read completes, even when dropping the file
+        unsafe {
+            let buf = chunk.buffer.ptr.add(chunk.filled);
+            ring.submit_read(fd, buf, len, chunk.index * CHUNK_SIZE + chunk.filled as u64, chunk.id)
+        }
+    }
+
+    fn ring(inner: &mut Inner) -> io::Result<&mut Ring> {
+        inner.ring.as_mut().ok_or_else(|| io::Error::other("io_uring not available"))
+    }
+
+    /// Handles the next completion.
+    fn reap(&self, inner: &mut Inner) -> io::Result<()> {
+        let completion = Self::ring(inner)?.wait()?;
+
+        if let Some(chunk) = self.complete(inner, completion) {
+            self.submit_chunk(inner, &chunk)?;
+            inner.chunks.push(chunk);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the chunk of `index` to be read.
+    fn wait_chunk<'a>(&self, inner: &'a mut Inner, index: u64) -> io::Result<&'a Chunk> {
+        loop {
+            let position = inner
+                .chunks
+                .iter()
+                .position(|chunk| chunk.wanted && chunk.index == index)
+                .ok_or_else(|| io::Error::other(format!("Chunk {index} was not submitted")))?;
+
+            match &inner.chunks[position].state {
+                ChunkState::Done => return Ok(&inner.chunks[position]),
+                ChunkState::Failed(_) => {
+                    let chunk = inner.chunks.remove(position);
+                    let ChunkState::Failed(err) = chunk.state else {
+                        unreachable!()
+                    };
+
+                    return Err(err);
+                }
+                ChunkState::InFlight => (),
+            }
+
+            self.reap(inner)?;
+        }
+    }
+
+    /// Handles a completion, returns the chunk if it was read partially and
+    /// the rest of it has to be submitted.
+    fn complete(&self, inner: &mut Inner, (id, res): (u64, i32)) -> Option<Chunk> {
+        let position = inner.chunks.iter().position(|chunk| chunk.id == id)?;
+
+        let chunk = &mut inner.chunks[position];
+        chunk.state = match res {
+            res if res == -libc::EINTR || res == -libc::EAGAIN => ChunkState::InFlight,
+            res if res < 0 => ChunkState::Failed(io::Error::from_raw_os_error(-res)),
+            0 => ChunkState::Done,
+            res => {
+                let start = chunk.filled;
+                chunk.filled += res as usize;
+
+                // O_DIRECT reads must start aligned, the end of the partial
+                // read is read again
+                let resume = match self.direct_file {
+                    Some(_) => chunk.filled - chunk.filled % ALIGNMENT,
+                    None => chunk.filled,
+                };
+
+                let chunk_end = chunk.index * CHUNK_SIZE + chunk.filled as u64;
+                // A short read that doesn't get any further is the end of a
+                // file truncated since it was opened
+                if chunk.filled < CHUNK_SIZE as usize && chunk_end < self.size && resume > start {
+                    chunk.filled = resume;
+
+                    ChunkState::InFlight
+                } else {
+                    ChunkState::Done
+                }
+            }
+        };
+
+        // Not wanted anymore, or in flight again for the rest of the chunk
+        if !chunk.wanted || matches!(chunk.state, ChunkState::InFlight) {
+            return Some(inner.chunks.swap_remove(position)).filter(|chunk| chunk.wanted);
+        }
+
+        None
+    }
+
+    fn pread(&self, offset: u64, size: u32) -> io::Result<Option<gst::Buffer>> {
+        backend::pread(&self.file, offset, size)
+    }
+}
+
+impl Drop for UringFile {
+    fn drop(&mut self) {
+        // The kernel may still be writing to the buffers of the reads in
+        // flight
+        let inner = self.inner.get_mut().unwrap();
+        while inner.chunks.iter().any(|chunk| matches!(chunk.state, ChunkState::InFlight)) {
+            let Some(Ok((id, _))) = inner.ring.as_mut().map(Ring::wait) else {
+                // Leak the buffers rather than having them overwritten
+                std::mem::forget(std::mem::take(&mut inner.chunks));
+                return;
+            };
+
+            inner.chunks.retain(|chunk| chunk.id != id);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-mod customsource;
+pub mod customsource;
 
 gst::plugin_define!(
     customsource,
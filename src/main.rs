@@ -1,3 +1,148 @@
+use gstcustomsource::customsource::pin;
+
+use ges::prelude::*;
+use gst::glib;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+fn usage(program: &str) {
+    println!("Usage: {program} pin --directory <DIR> [--project <PROJECT.xges>...] [URI...]");
+    println!("       {program} status --directory <DIR> [--project <PROJECT.xges>...] [URI...]");
+    println!();
+    println!("pin reads the files completely into DIR so that customsource elements with");
+    println!("`pin-directory=DIR offline=true` can play them without access to the originals.");
+    println!("status lists which of the files are pinned. --project adds all the assets");
+    println!("referenced by a GES project.");
+}
+
+/// Prints `message` and exits with an error.
+fn exit_with_error(message: impl std::fmt::Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+/// URIs of the `GESUriClip` assets of a GES project.
+fn project_uris(project: &Path) -> Result<Vec<String>, String> {
+    let path = std::fs::canonicalize(project).map_err(|err| format!("Could not read project {project:?}: {err}"))?;
+    let uri = glib::filename_to_uri(&path, None).map_err(|err| format!("Invalid project path {path:?}: {err}"))?;
+
+    let project = ges::Project::new(Some(&uri));
+    let main_loop = glib::MainLoop::new(None, false);
+    let uris = Rc::new(RefCell::new(Vec::new()));
+    let error = Rc::new(RefCell::new(None));
+    let done = Rc::new(Cell::new(false));
+
+    // The assets are listed before GES discovers them, missing files are
+    // still pinned or reported
+    project.connect_asset_loading(glib::clone!(@strong uris => move |_, asset| {
+        if asset.extractable_type() == ges::UriClip::static_type() {
+            uris.borrow_mut().push(asset.id().to_string());
+        }
+    }));
+    project.connect_loaded(glib::clone!(@strong main_loop, @strong done => move |_, _| {
+        done.set(true);
+        main_loop.quit();
+    }));
+    project.connect_error_loading(glib::clone!(@strong main_loop, @strong done, @strong error => move |_, _, err| {
+        *error.borrow_mut() = Some(err.to_string());
+        done.set(true);
+        main_loop.quit();
+    }));
+
+    project.extract().map_err(|err| format!("Could not load project {path:?}: {err}"))?;
+    // Empty projects are loaded right away
+    if !done.get() {
+        main_loop.run();
+    }
+
+    if let Some(err) = error.take() {
+        return Err(format!("Could not load project {path:?}: {err}"));
+    }
+
+    // Clips of the same asset may be anywhere in the timeline
+    let mut seen = HashSet::new();
+    let mut uris = uris.take();
+    uris.retain(|uri| seen.insert(uri.clone()));
+
+    Ok(uris)
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().collect();
+
+    let Some(command) = args.get(1).filter(|command| ["pin", "status"].contains(&command.as_str())) else {
+        usage(&args[0]);
+        return;
+    };
+
+    gst::init().expect("Could not init GStreamer");
+    ges::init().expect("Could not init GES");
+    gstcustomsource::plugin_register_static().expect("Could not register customsource plugin");
+
+    let mut directory = None;
+    let mut uris = Vec::new();
+    let mut arguments = args[2..].iter();
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "--directory" => directory = arguments.next().map(PathBuf::from),
+            "--project" => {
+                let Some(project) = arguments.next() else {
+                    exit_with_error("--project needs a file");
+                };
+
+                uris.extend(project_uris(Path::new(project)).unwrap_or_else(|err| exit_with_error(err)));
+            }
+            uri => uris.push(uri.to_owned()),
+        }
+    }
+
+    let Some(directory) = directory else {
+        usage(&args[0]);
+        return;
+    };
+
+    if let Err(err) = std::fs::create_dir_all(&directory) {
+        exit_with_error(format!("Could not create pin directory {directory:?}: {err}"));
+    }
+
+    let mut failed = false;
+    for uri in &uris {
+        match command.as_str() {
+            "status" => match pin::is_pinned(&directory, uri) {
+                Ok(true) => println!("pinned      {uri}"),
+                Ok(false) => println!("not pinned  {uri}"),
+                Err(err) => {
+                    println!("invalid     {uri}: {err}");
+                    failed = true;
+                }
+            },
+            _ => {
+                let res = pin::pin(&directory, uri, |pinned, size| {
+                    let percent = match size {
+                        0 => 100,
+                        size => pinned * 100 / size,
+                    };
+
+                    print!("\r{percent:3}% {uri}");
+                    let _ = std::io::stdout().flush();
+                });
+
+                match res {
+                    Ok(()) => println!(),
+                    Err(err) => {
+                        println!("\rfailed {uri}: {err}");
+                        failed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
 }